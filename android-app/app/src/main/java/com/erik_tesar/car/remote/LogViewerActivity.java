@@ -16,6 +16,13 @@ import java.io.IOException;
 import java.io.InputStreamReader;
 
 public class LogViewerActivity extends AppCompatActivity {
+    private static final int AUDIT_ENTRIES = 50;
+
+    static {
+        System.loadLibrary("car_remote");
+    }
+
+    private native String recentAuditEntries(int limit);
 
     private final Handler mainHandler = new Handler(Looper.getMainLooper());
     private final StringBuilder logBuffer = new StringBuilder();
@@ -55,6 +62,7 @@ public class LogViewerActivity extends AppCompatActivity {
         this.logTextView = logTextView;
         this.scrollView = scrollView;
 
+        showAuditLog();
         showLogs();
     }
 
@@ -64,6 +72,15 @@ public class LogViewerActivity extends AppCompatActivity {
         super.onDestroy();
     }
 
+    private void showAuditLog() {
+        String entries = recentAuditEntries(AUDIT_ENTRIES);
+        logTextView.append("Recent commands (newest first):\n");
+        if (entries != null) {
+            logTextView.append(entries);
+        }
+        logTextView.append("\n\nLogs:\n");
+    }
+
     private void showLogs() {
         Log.i("LOGS", "Starting thread...");
         new Thread(() -> {
@@ -8,6 +8,7 @@ import android.os.Bundle;
 import android.util.Log;
 import android.view.View;
 import android.widget.ImageView;
+import android.widget.Toast;
 
 import androidx.annotation.NonNull;
 import androidx.appcompat.app.AppCompatActivity;
@@ -16,6 +17,13 @@ import java.util.ArrayList;
 import java.util.List;
 
 public class MainActivity extends AppCompatActivity {
+    static {
+        System.loadLibrary("car_remote");
+    }
+
+    // the next start of the service scans for the devices again
+    private native void forgetDevices();
+
     @Override
     protected void onCreate(Bundle savedInstanceState) {
         super.onCreate(savedInstanceState);
@@ -34,6 +42,11 @@ public class MainActivity extends AppCompatActivity {
             Intent intent = new Intent(v.getContext(), LogViewerActivity.class);
             startActivity(intent);
         });
+        img.setOnLongClickListener(v -> {
+            forgetDevices();
+            Toast.makeText(v.getContext(), "Forgot BLE devices, restart to scan again", Toast.LENGTH_LONG).show();
+            return true;
+        });
    }
 
     private boolean checkPermission(Context context) {
@@ -4,34 +4,70 @@ import android.app.Notification;
 import android.app.NotificationChannel;
 import android.app.NotificationManager;
 import android.app.Service;
+import android.bluetooth.BluetoothDevice;
+import android.bluetooth.BluetoothGatt;
+import android.bluetooth.BluetoothGattCallback;
+import android.bluetooth.BluetoothManager;
+import android.bluetooth.BluetoothProfile;
+import android.content.BroadcastReceiver;
 import android.content.ContentResolver;
 import android.content.Context;
 import android.content.Intent;
+import android.content.IntentFilter;
 import android.database.Cursor;
 import android.os.IBinder;
 import android.provider.ContactsContract;
+import android.telephony.SmsManager;
 import android.util.Log;
 
 import java.util.ArrayList;
 import java.util.List;
+import java.util.concurrent.CountDownLatch;
+import java.util.concurrent.TimeUnit;
+import java.util.concurrent.atomic.AtomicInteger;
 
 
 public class RustService extends Service {
     private static final String CHANNEL_ID = "RustServiceChannel";
     private static final int NOTIFICATION_ID = 1;
+    // outside of the signed byte android reports
+    private static final int RSSI_UNAVAILABLE = Integer.MIN_VALUE;
+    private static final int RSSI_TIMEOUT_SECONDS = 5;
+    // BluetoothDevice.PAIRING_VARIANT_DISPLAY_PASSKEY is hidden
+    private static final int PAIRING_VARIANT_DISPLAY_PASSKEY = 4;
 
     public static boolean isRunning = false;
+    private static RustService instance;
     static {
         System.loadLibrary("car_remote");
     }
 
     private native void startService();
     private native void provideAuthorizedPhoneNumbers(List<String> numbers);
+    private native void providePasskey(String address, int passkey);
+
+    // the devices enter the passkey android displays, rust sends it to them
+    // sealed with the pairing key
+    private final BroadcastReceiver pairingReceiver = new BroadcastReceiver() {
+        @Override
+        public void onReceive(Context context, Intent intent) {
+            int variant = intent.getIntExtra(BluetoothDevice.EXTRA_PAIRING_VARIANT, BluetoothDevice.ERROR);
+            int passkey = intent.getIntExtra(BluetoothDevice.EXTRA_PAIRING_KEY, BluetoothDevice.ERROR);
+            BluetoothDevice device = intent.getParcelableExtra(BluetoothDevice.EXTRA_DEVICE, BluetoothDevice.class);
+            if (variant != PAIRING_VARIANT_DISPLAY_PASSKEY || passkey < 0 || device == null) {
+                return;
+            }
+            providePasskey(device.getAddress(), passkey);
+        }
+    };
 
    @Override
     public void onCreate() {
         super.onCreate();
         isRunning = true;
+        instance = this;
+        registerReceiver(pairingReceiver, new IntentFilter(BluetoothDevice.ACTION_PAIRING_REQUEST),
+                Context.RECEIVER_EXPORTED);
     }
 
     @Override
@@ -54,6 +90,8 @@ public class RustService extends Service {
     public void onDestroy() {
         super.onDestroy();
         isRunning = false;
+        instance = null;
+        unregisterReceiver(pairingReceiver);
     }
 
     @Override
@@ -61,6 +99,58 @@ public class RustService extends Service {
         return null;
     }
 
+    // called from rust to reply to queries
+    public static void sendSms(String number, String text) {
+        SmsManager smsManager = SmsManager.getDefault();
+        ArrayList<String> parts = smsManager.divideMessage(text);
+        smsManager.sendMultipartTextMessage(number, null, parts, null, null);
+        Log.i("Rust", "Sent sms to " + number);
+    }
+
+    // called from rust, android only reports the RSSI of a connection to a
+    // GATT client, so a second client is registered on the existing link
+    public static int readRssi(String address) throws InterruptedException {
+        RustService service = instance;
+        if (service == null) {
+            return RSSI_UNAVAILABLE;
+        }
+        BluetoothManager manager = service.getSystemService(BluetoothManager.class);
+        BluetoothDevice device = manager.getAdapter().getRemoteDevice(address);
+        if (manager.getConnectionState(device, BluetoothProfile.GATT) != BluetoothProfile.STATE_CONNECTED) {
+            return RSSI_UNAVAILABLE;
+        }
+
+        CountDownLatch done = new CountDownLatch(1);
+        AtomicInteger rssi = new AtomicInteger(RSSI_UNAVAILABLE);
+        BluetoothGattCallback callback = new BluetoothGattCallback() {
+            @Override
+            public void onConnectionStateChange(BluetoothGatt gatt, int status, int newState) {
+                if (newState != BluetoothProfile.STATE_CONNECTED || !gatt.readRemoteRssi()) {
+                    done.countDown();
+                }
+            }
+
+            @Override
+            public void onReadRemoteRssi(BluetoothGatt gatt, int value, int status) {
+                if (status == BluetoothGatt.GATT_SUCCESS) {
+                    rssi.set(value);
+                }
+                done.countDown();
+            }
+        };
+        BluetoothGatt gatt = device.connectGatt(service, false, callback, BluetoothDevice.TRANSPORT_LE);
+        if (gatt == null) {
+            return RSSI_UNAVAILABLE;
+        }
+        try {
+            done.await(RSSI_TIMEOUT_SECONDS, TimeUnit.SECONDS);
+        } finally {
+            // only unregisters this client, the link stays up for btleplug
+            gatt.close();
+        }
+        return rssi.get();
+    }
+
     private void createNotificationChannel() {
         NotificationChannel channel = new NotificationChannel(CHANNEL_ID,
                 "Rust Service Channel", NotificationManager.IMPORTANCE_HIGH);
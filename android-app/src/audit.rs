@@ -0,0 +1,222 @@
+//! Append-only log of every received SMS and what has been done with it
+//!
+//! Entries are stored as JSON lines in [`AUDIT_LOG_PATH`]. If the file grows
+//! beyond [`MAX_LOG_SIZE`] it is rotated to `audit.log.1`, `audit.log.2`, ...
+//! and the oldest one is dropped.
+
+use std::{
+    fmt::Display,
+    io::{BufRead, BufReader, ErrorKind},
+    time::SystemTime,
+};
+
+use jni::{
+    objects::JClass,
+    sys::{jint, jstring},
+    JNIEnv,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+use crate::schema::Command;
+
+const AUDIT_LOG_PATH: &str = "/data/data/com.erik_tesar.car.remote/audit.log";
+const MAX_LOG_SIZE: u64 = 256 * 1024;
+const ROTATED_LOGS: usize = 3;
+
+/// Serializes appends and rotation
+static AUDIT_LOG: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "result", content = "reason")]
+pub enum Verification {
+    NotChecked,
+    Valid,
+    Invalid(String),
+}
+
+/// Why a SMS was executed or not
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyDecision {
+    Pending,
+    Accepted,
+    RateLimited,
+    LockedOut,
+    Unauthorized,
+    InvalidSignature,
+    Expired,
+    ExpiresTooLate,
+    Replayed,
+    SetupRequired,
+    Setup,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "result", content = "reason")]
+pub enum BleOutcome {
+    NotSent,
+    Dispatched,
+    Ok,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds
+    pub received_at: u64,
+    /// Unix timestamp in seconds
+    pub completed_at: Option<u64>,
+    /// Number as reported by the network
+    pub number: String,
+    /// Fingerprint of the key the command was verified with
+    pub key_id: Option<String>,
+    /// `jwt` or `compact`
+    pub format: Option<String>,
+    pub command: Option<Command>,
+    pub verification: Verification,
+    pub decision: PolicyDecision,
+    pub ble: BleOutcome,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl AuditEntry {
+    pub fn new(number: &str) -> Self {
+        Self {
+            received_at: unix_now(),
+            completed_at: None,
+            number: number.to_string(),
+            key_id: None,
+            format: None,
+            command: None,
+            verification: Verification::NotChecked,
+            decision: PolicyDecision::Pending,
+            ble: BleOutcome::NotSent,
+        }
+    }
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {:?} {:?} {:?}",
+            self.received_at,
+            self.number,
+            self.decision,
+            self.command,
+            self.ble
+        )?;
+        if let Verification::Invalid(reason) = &self.verification {
+            write!(f, " ({reason})")?;
+        }
+        Ok(())
+    }
+}
+
+fn rotated_path(index: usize) -> String {
+    format!("{AUDIT_LOG_PATH}.{index}")
+}
+
+async fn rotate() -> std::io::Result<()> {
+    for index in (1..ROTATED_LOGS).rev() {
+        match tokio::fs::rename(rotated_path(index), rotated_path(index + 1))
+            .await
+        {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    tokio::fs::rename(AUDIT_LOG_PATH, rotated_path(1)).await
+}
+
+pub async fn append(mut entry: AuditEntry) {
+    entry.completed_at = Some(unix_now());
+    info!("Audit: {entry}");
+    let mut line = match serde_json::to_vec(&entry) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Failed to serialize audit entry: {e}");
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    let _guard = AUDIT_LOG.lock().await;
+    if let Ok(metadata) = tokio::fs::metadata(AUDIT_LOG_PATH).await {
+        if metadata.len() > MAX_LOG_SIZE {
+            if let Err(e) = rotate().await {
+                error!("Failed to rotate audit log: {e}");
+            }
+        }
+    }
+    let res = async {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(AUDIT_LOG_PATH)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+    .await;
+    if let Err(e) = res {
+        error!("Failed to write audit entry: {e}");
+    }
+}
+
+fn read_entries(path: &str) -> Vec<AuditEntry> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            error!("Failed to open audit log {path}: {e}");
+            return vec![];
+        }
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+/// Returns up to `limit` entries, newest first
+pub fn recent(limit: usize) -> Vec<AuditEntry> {
+    let mut entries: Vec<AuditEntry> = vec![];
+    let paths = std::iter::once(AUDIT_LOG_PATH.to_string())
+        .chain((1..=ROTATED_LOGS).map(rotated_path));
+    for path in paths {
+        if entries.len() >= limit {
+            break;
+        }
+        entries.extend(read_entries(&path).into_iter().rev());
+    }
+    entries.truncate(limit);
+    entries
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_erik_1tesar_car_remote_LogViewerActivity_recentAuditEntries(
+    env: JNIEnv,
+    _this: JClass,
+    limit: jint,
+) -> jstring {
+    let text = recent(limit.max(0) as usize)
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    match env.new_string(text) {
+        Ok(text) => text.into_inner(),
+        Err(e) => {
+            error!("Failed to pass audit entries to java: {e:#?}");
+            std::ptr::null_mut()
+        }
+    }
+}
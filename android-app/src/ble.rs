@@ -1,42 +1,166 @@
-use std::{collections::BTreeSet, convert::Infallible, time::Duration};
+use std::{
+    collections::BTreeSet,
+    convert::Infallible,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+    time::Duration,
+};
 
 use btleplug::{
     api::{
-        Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter,
-        WriteType,
+        BDAddr, Central, CentralEvent, Manager as _, Peripheral as _,
+        PeripheralProperties, ScanFilter, WriteType,
     },
     platform::{Adapter, Manager, Peripheral},
 };
 use color_eyre::eyre::eyre;
-use futures_util::{Stream, StreamExt};
-use jni::JNIEnv;
+use futures_util::{future::ready, Stream, StreamExt};
+use jni::{
+    objects::{JClass, JString},
+    sys::jint,
+    JNIEnv,
+};
 use tokio::{
     sync::{
+        broadcast::{self, error::RecvError},
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, watch, RwLock,
     },
     task::JoinHandle,
-    time::sleep,
+    time::{sleep, timeout, timeout_at, Instant},
 };
+use uuid::Uuid;
 
 use crate::{
-    log_error,
+    config,
+    devices::KnownDevices,
+    engine::{self, EngineSource},
+    health, log_error,
+    queue::{self, Device},
     schema::{
-        self, Command, DoorControllerCommand, EngineCommand, DOOR_SERVICE_UUID,
+        self, Arbitration, Command, DoorControllerCommand, EngineCommand,
+        KeyPosition, StarterDiagnostics, DOOR_SERVICE_UUID,
         ENGINE_SERVICE_UUID,
     },
+    status,
 };
 
 pub static STARTER: RwLock<Option<Peripheral>> = RwLock::const_new(None);
 pub static DOOR_CONTROLLER: RwLock<Option<Peripheral>> =
     RwLock::const_new(None);
-pub static ENGINE_STATUS: RwLock<EngineCommand> =
-    RwLock::const_new(EngineCommand::Off);
+/// Incremented whenever [`STARTER`] is replaced by a new connection
+static STARTER_GENERATION: LazyLock<watch::Sender<u64>> =
+    LazyLock::new(|| watch::channel(0).0);
+
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Passkeys android displays while pairing, with the address of the device
+static PASSKEYS: LazyLock<broadcast::Sender<(BDAddr, u32)>> =
+    LazyLock::new(|| broadcast::channel(4).0);
+/// How long a device may take to request the pairing
+const PASSKEY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Reads of the challenge until the device has to have entered the passkey
+const AUTHORIZED_POLLS: u32 = 5;
+
+/// 32 random bytes the passkeys for the devices are sealed with, see the README
+const PAIRING_KEY: &[u8; 32] = include_bytes!(env!("PAIRING_KEY_PATH"));
+/// 32 random bytes engine commands are authenticated with, see the README
+const COMMAND_KEY: &[u8; 32] = include_bytes!(env!("COMMAND_KEY_PATH"));
+
+/// Resolves once the command has been executed or abandoned
+pub type BleAck = oneshot::Receiver<color_eyre::Result<()>>;
+
+/// A [`Command`] for the BLE listener and the channel to report its result
+pub struct BleRequest {
+    pub id: u64,
+    pub command: Command,
+    /// The command is abandoned instead of being executed late
+    pub deadline: Instant,
+    pub reply: oneshot::Sender<color_eyre::Result<()>>,
+}
+
+impl BleRequest {
+    pub fn new(command: Command) -> (Self, BleAck) {
+        let (reply, receiver) = oneshot::channel();
+        let timeout =
+            Duration::from_secs(config::get().ble.command_timeout_secs);
+        let request = Self {
+            id: REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            command,
+            deadline: Instant::now() + timeout,
+            reply,
+        };
+        (request, receiver)
+    }
+}
+
+/// The starter rejected an engine state because the physical key is in use
+#[derive(Debug)]
+pub struct KeyInUse(pub KeyPosition);
+
+impl Display for KeyInUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected: physical key in use ({:?})", self.0)
+    }
+}
+
+impl std::error::Error for KeyInUse {}
+
+/// The deadline of a request passed before its write was issued
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "abandoned: deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+fn check_deadline(deadline: Instant) -> Result<(), DeadlineExceeded> {
+    if Instant::now() >= deadline {
+        Err(DeadlineExceeded)
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs a step that has no effect on the device if it is cancelled
+///
+/// Checked before polling `fut` because [`timeout_at`] polls it first, which
+/// would let an expired request start.
+async fn before_deadline<T>(
+    deadline: Instant,
+    fut: impl Future<Output = T>,
+) -> Result<T, DeadlineExceeded> {
+    check_deadline(deadline)?;
+    timeout_at(deadline, fut)
+        .await
+        .map_err(|_| DeadlineExceeded)
+}
+
+/// Sends `command` to the BLE listener and waits until it has been executed
+/// or abandoned
+pub async fn send(
+    sender: &UnboundedSender<BleRequest>,
+    command: Command,
+) -> color_eyre::Result<()> {
+    let (request, receiver) = BleRequest::new(command);
+    sender
+        .send(request)
+        .map_err(|_| eyre!("BLE listener hung up"))?;
+    receiver.await?
+}
 
 pub async fn init(
     env: &JNIEnv<'_>,
 ) -> color_eyre::Result<(
-    UnboundedSender<Command>,
+    UnboundedSender<BleRequest>,
     JoinHandle<Result<(), color_eyre::Report>>,
     JoinHandle<Result<Infallible, color_eyre::Report>>,
     JoinHandle<Result<Infallible, color_eyre::Report>>,
@@ -55,7 +179,7 @@ pub async fn init(
         .ok_or(eyre!("No adapter found"))?;
     let events = adapter.events().await?;
 
-    let (sender, receiver) = unbounded_channel::<Command>();
+    let (sender, receiver) = unbounded_channel::<BleRequest>();
 
     let events = tokio::spawn(handle_events(events));
 
@@ -109,36 +233,98 @@ async fn handle_events(
     Ok(())
 }
 async fn listen(
-    mut receiver: UnboundedReceiver<Command>,
+    mut receiver: UnboundedReceiver<BleRequest>,
 ) -> color_eyre::Result<Infallible> {
     info!("Listening for commands that should be sent over BLE");
-    while let Some(command) = receiver.recv().await {
-        info!("Sending command via BLE: {command:?}");
-        match command {
-            Command::DoorController(command) => {
-                info!("Sending {command:?} to door controller handler");
-                let _ = log_error(
-                    "Door command handler failed",
-                    handle_door_command(command).await,
-                );
+    let intake = async {
+        while let Some(request) = receiver.recv().await {
+            debug!("Queueing command {}: {:?}", request.id, request.command);
+            queue::push(request);
+        }
+        Err(eyre!("Channel closed"))
+    };
+    // an unreachable door controller must not delay engine commands
+    tokio::select! {
+        res = intake => res,
+        res = work(Device::Starter) => res,
+        res = work(Device::DoorController) => res,
+    }
+}
+
+async fn work(device: Device) -> color_eyre::Result<Infallible> {
+    loop {
+        let (
+            BleRequest {
+                id,
+                command,
+                deadline,
+                reply,
+            },
+            _link,
+        ) = queue::next(device).await;
+        info!("Sending command {id} via BLE: {command:?}");
+        // a write that has been issued is never cancelled, the device might
+        // have applied it already
+        let res = execute_with_retries(&command, deadline).await;
+        let res = log_error("BLE command failed", res);
+        status::record_command(command, &res).await;
+        // the caller might not be interested in the result
+        let _ = reply.send(res);
+    }
+}
+
+async fn execute_with_retries(
+    command: &Command,
+    deadline: Instant,
+) -> color_eyre::Result<()> {
+    let config = &config::get().ble;
+    let mut backoff = Duration::from_millis(config.retry_backoff_ms);
+    let mut attempt = 0;
+    loop {
+        match execute(command, deadline).await {
+            Ok(()) => return Ok(()),
+            // retrying would only be rejected again
+            Err(e) if e.downcast_ref::<KeyInUse>().is_some() => return Err(e),
+            Err(e) if e.downcast_ref::<DeadlineExceeded>().is_some() => {
+                return Err(e)
             }
-            Command::Engine(command) => {
-                info!("Sending {command:?} to engine handler");
-                let _ = log_error(
-                    "Engine command handler failed",
-                    handle_engine_command(command).await,
-                );
+            Err(e) if attempt < config.max_retries => {
+                attempt += 1;
+                warn!("Executing {command:?} failed, retry {attempt} in {backoff:?}: {e}");
+                before_deadline(deadline, sleep(backoff)).await?;
+                backoff = (backoff * 2)
+                    .min(Duration::from_millis(config.max_retry_backoff_ms));
             }
-        };
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn execute(
+    command: &Command,
+    deadline: Instant,
+) -> color_eyre::Result<()> {
+    match command.to_owned() {
+        Command::DoorController(command) => {
+            info!("Sending {command:?} to door controller handler");
+            handle_door_command(command, deadline).await
+        }
+        Command::Engine(command) => {
+            info!("Sending {command:?} to engine handler");
+            handle_engine_command(command, deadline).await
+        }
+        Command::Query(query) => {
+            Err(eyre!("{query:?} is a query and cannot be sent via BLE"))
+        }
     }
-    Err(eyre!("Channel closed"))
 }
 
 async fn handle_door_command(
     command: DoorControllerCommand,
+    deadline: Instant,
 ) -> color_eyre::Result<()> {
     info!("Checking if door controller is connected");
-    try_reconnect_door_controller().await;
+    before_deadline(deadline, try_reconnect_door_controller()).await?;
 
     let needed_char = match command {
         DoorControllerCommand::Lock | DoorControllerCommand::Unlock => {
@@ -164,18 +350,22 @@ async fn handle_door_command(
         | DoorControllerCommand::WindowLeftDown
         | DoorControllerCommand::WindowRightDown => 1,
     };
+    check_deadline(deadline)?;
     info!("Writing {command} to characteristic {}", char.uuid);
+    let start = Instant::now();
     door_controller
         .write(&char, &[command], WriteType::WithResponse)
         .await?;
+    health::record_write(Device::DoorController, start.elapsed());
 
     Ok(())
 }
 
 async fn handle_engine_command(
     command: EngineCommand,
+    deadline: Instant,
 ) -> color_eyre::Result<()> {
-    try_reconnect_starter().await;
+    before_deadline(deadline, try_reconnect_starter()).await?;
 
     let guard = STARTER.read().await;
     let starter = guard.as_ref().ok_or(eyre!("starter not initalized"))?;
@@ -183,102 +373,367 @@ async fn handle_engine_command(
     let char = starter
         .characteristics()
         .iter()
-        .find(|c| c.uuid == schema::ENGINE_STATE_CHAR)
+        .find(|c| c.uuid == schema::ENGINE_COMMAND_CHAR)
         .cloned()
         .ok_or(eyre!(
             "Starter is missing characteristic for {command:?}: {}",
-            schema::ENGINE_STATE_CHAR
+            schema::ENGINE_COMMAND_CHAR
         ))?;
-    let command: u8 = match command {
+    let value: u8 = match command {
         EngineCommand::Off => 0,
         EngineCommand::Radio => 1,
         EngineCommand::Engine => 2,
         EngineCommand::Ignition => 3,
     };
-    starter
-        .write(&char, &[command], WriteType::WithResponse)
-        .await?;
+    // the starter only accepts counters above the last accepted one
+    let counter: [u8; 4] = before_deadline(deadline, starter.read(&char))
+        .await??
+        .try_into()
+        .map_err(|_| eyre!("Invalid command counter"))?;
+    let counter = u32::from_le_bytes(counter)
+        .checked_add(1)
+        .ok_or(eyre!("Command counter exhausted"))?;
+    check_deadline(deadline)?;
+    let start = Instant::now();
+    let res = starter
+        .write(
+            &char,
+            &firmware_auth::command::Command {
+                state: value,
+                counter,
+            }
+            .encode(COMMAND_KEY),
+            WriteType::WithResponse,
+        )
+        .await;
+    health::record_write(Device::Starter, start.elapsed());
+    if let Err(e) = res {
+        // android does not pass the ATT error code through, ask the starter
+        // whether the key is the reason
+        drop(guard);
+        if let Ok(key) = read_key_state().await {
+            if key.position != KeyPosition::Off {
+                return Err(KeyInUse(key.position).into());
+            }
+        }
+        return Err(e.into());
+    }
+    // confirmed once the starter notifies the change
+    engine::set(command, EngineSource::Assumed).await;
     Ok(())
 }
 
+fn parse_engine_state(value: &[u8]) -> color_eyre::Result<EngineCommand> {
+    Ok(
+        match value.first().ok_or(eyre!("Invalid response format"))? {
+            0 => EngineCommand::Off,
+            1 => EngineCommand::Radio,
+            2 => EngineCommand::Engine,
+            3 => EngineCommand::Ignition,
+            _ => return Err(eyre!("Invalid response format")),
+        },
+    )
+}
+
+/// Reads the engine state from the starter
+pub async fn read_engine_state() -> color_eyre::Result<EngineCommand> {
+    if !try_reconnect_starter().await {
+        return Err(eyre!("Starter not connected, engine state unknown"));
+    }
+    let starter = STARTER
+        .read()
+        .await
+        .clone()
+        .ok_or(eyre!("starter not initalized"))?;
+    let char = starter
+        .characteristics()
+        .iter()
+        .find(|c| c.uuid == schema::ENGINE_STATE_CHAR)
+        .cloned()
+        .ok_or(eyre!("starter does not have engine state char"))?;
+    let val = parse_engine_state(&starter.read(&char).await?)?;
+    engine::set(val, EngineSource::Read).await;
+    Ok(val)
+}
+
+/// Key position and arbitration as reported by the starter
+#[derive(Debug, Clone, Copy)]
+pub struct KeyState {
+    pub position: KeyPosition,
+    pub arbitration: Arbitration,
+}
+
+impl KeyState {
+    /// Anything but an unused key means someone is in the car
+    pub fn occupied(&self) -> bool {
+        self.position != KeyPosition::Off
+            || self.arbitration == Arbitration::BleHeldWhileKeyRadio
+    }
+}
+
+/// Reads the key state if the starter is connected, without reconnecting
+pub async fn read_key_state() -> color_eyre::Result<KeyState> {
+    let starter = STARTER
+        .read()
+        .await
+        .clone()
+        .ok_or(eyre!("starter not initalized"))?;
+    if !starter.is_connected().await? {
+        return Err(eyre!("Starter not connected"));
+    }
+    let read = |uuid| {
+        let starter = &starter;
+        async move {
+            let char = starter
+                .characteristics()
+                .iter()
+                .find(|c| c.uuid == uuid)
+                .cloned()
+                .ok_or(eyre!("Starter is missing characteristic {uuid}"))?;
+            let value = starter.read(&char).await?;
+            value
+                .first()
+                .copied()
+                .ok_or(eyre!("Invalid response format"))
+        }
+    };
+    let position = read(schema::KEY_POSITION_CHAR).await?;
+    let arbitration = read(schema::ARBITRATION_CHAR).await?;
+    Ok(KeyState {
+        position: KeyPosition::from_byte(position)
+            .ok_or(eyre!("Invalid key position {position}"))?,
+        arbitration: Arbitration::from_byte(arbitration)
+            .ok_or(eyre!("Invalid arbitration {arbitration}"))?,
+    })
+}
+
+/// Reads the diagnostics if the starter is connected, without reconnecting
+pub async fn read_diagnostics() -> color_eyre::Result<StarterDiagnostics> {
+    let starter = STARTER
+        .read()
+        .await
+        .clone()
+        .ok_or(eyre!("starter not initalized"))?;
+    if !starter.is_connected().await? {
+        return Err(eyre!("Starter not connected"));
+    }
+    let char = starter
+        .characteristics()
+        .iter()
+        .find(|c| c.uuid == schema::DIAGNOSTICS_CHAR)
+        .cloned()
+        .ok_or(eyre!("Starter does not have a diagnostics characteristic"))?;
+    let value = starter.read(&char).await?;
+    postcard::from_bytes(&value)
+        .map_err(|e| eyre!("Invalid starter diagnostics: {e}"))
+}
+
+/// Reconnects `device` if needed and returns its peripheral
+pub async fn connected(device: Device) -> color_eyre::Result<Peripheral> {
+    let (peripheral, connected) = match device {
+        Device::Starter => (&STARTER, try_reconnect_starter().await),
+        Device::DoorController => {
+            (&DOOR_CONTROLLER, try_reconnect_door_controller().await)
+        }
+    };
+    if !connected {
+        return Err(eyre!("{device:?} not connected"));
+    }
+    peripheral
+        .read()
+        .await
+        .clone()
+        .ok_or(eyre!("{device:?} not initalized"))
+}
+
+/// Reads the panic recorded by the device before its last reset and clears it
+/// so it is reported only once
+pub async fn take_panic_record(
+    device: Device,
+) -> color_eyre::Result<Option<String>> {
+    let (peripheral, uuid) = match device {
+        Device::Starter => (&STARTER, schema::STARTER_PANIC_CHAR),
+        Device::DoorController => (&DOOR_CONTROLLER, schema::DOOR_PANIC_CHAR),
+    };
+    let peripheral = peripheral
+        .read()
+        .await
+        .clone()
+        .ok_or(eyre!("{device:?} not initalized"))?;
+    if !peripheral.is_connected().await? {
+        return Err(eyre!("{device:?} not connected"));
+    }
+    let char = peripheral
+        .characteristics()
+        .iter()
+        .find(|c| c.uuid == uuid)
+        .cloned()
+        .ok_or(eyre!("{device:?} does not have a panic characteristic"))?;
+    let value = peripheral.read(&char).await?;
+    if value.is_empty() {
+        return Ok(None);
+    }
+    peripheral
+        .write(&char, &[0], WriteType::WithResponse)
+        .await?;
+    Ok(Some(String::from_utf8_lossy(&value).into_owned()))
+}
+
+/// Keeps [`engine::EngineStatus`] in sync with the starter, subscribing again on
+/// every new connection
 async fn update_engine_state() -> color_eyre::Result<Infallible> {
     // give the scanner some time to find the starter and connect
     sleep(Duration::from_secs(10)).await;
     loop {
         if !try_reconnect_starter().await {
             warn!("Engine state updater could not connect, retrying...");
+            engine::set_unknown().await;
             sleep(Duration::from_secs(1)).await;
             continue;
         }
-        let guard = STARTER.read().await;
-        let starter = guard.as_ref().ok_or(eyre!("starter not initalized"))?;
-        let _ = starter.discover_services().await;
-        let char = starter
-            .characteristics()
-            .iter()
-            .find(|c| c.uuid == schema::ENGINE_STATE_CHAR)
-            .cloned()
-            .ok_or(eyre!("starter does not have engine state char"))?;
-        starter.subscribe(&char).await?;
-        use futures_util::future::ready;
-        use futures_util::StreamExt;
-        let mut stream = starter
-            .notifications()
-            .await?
-            .filter(|notficiation| ready(notficiation.uuid == char.uuid));
-        drop(guard);
-        debug!("Listening for engine state updates");
-        while let Some(update) = stream.next().await {
-            let val = match update
-                .value
-                .first()
-                .ok_or(eyre!("Invalid response format"))?
-            {
-                0 => EngineCommand::Off,
-                1 => EngineCommand::Radio,
-                2 => EngineCommand::Engine,
-                3 => EngineCommand::Ignition,
-                _ => return Err(eyre!("Invalid response format")),
-            };
-            debug!("Updating engine state to {val:?}");
-            let mut state = ENGINE_STATUS.write().await;
-            *state = val;
+        let mut generation = STARTER_GENERATION.subscribe();
+        // cloned so no guard is held while waiting for notifications
+        let Some(starter) = STARTER.read().await.clone() else {
+            continue;
+        };
+        if let Err(e) = follow_engine_state(&starter, &mut generation).await {
+            warn!("Engine state subscription ended: {e}");
         }
+        // notifications might be missed until the subscription is renewed
+        engine::set_unknown().await;
     }
 }
-pub async fn search(adapter: &Adapter) -> color_eyre::Result<()> {
-    adapter
-        .start_scan(ScanFilter {
-            services: vec![
-                schema::DOOR_SERVICE_UUID,
-                schema::ENGINE_SERVICE_UUID,
-            ],
-        })
-        .await?;
 
-    info!("Scanning for BLE devices");
+async fn follow_engine_state(
+    starter: &Peripheral,
+    generation: &mut watch::Receiver<u64>,
+) -> color_eyre::Result<()> {
+    if starter.characteristics().is_empty() {
+        starter.discover_services().await?;
+    }
+    let char = starter
+        .characteristics()
+        .iter()
+        .find(|c| c.uuid == schema::ENGINE_STATE_CHAR)
+        .cloned()
+        .ok_or(eyre!("starter does not have engine state char"))?;
+    starter.subscribe(&char).await?;
+    let mut stream = std::pin::pin!(starter
+        .notifications()
+        .await?
+        .filter(|notification| ready(notification.uuid == char.uuid)));
+    // changes before the subscription are not notified
+    let val = parse_engine_state(&starter.read(&char).await?)?;
+    engine::set(val, EngineSource::Read).await;
+
+    debug!("Listening for engine state updates");
+    loop {
+        tokio::select! {
+            update = stream.next() => {
+                let update =
+                    update.ok_or(eyre!("Notification stream closed"))?;
+                let val = parse_engine_state(&update.value)?;
+                engine::set(val, EngineSource::Notification).await;
+            }
+            _ = generation.changed() => {
+                info!("Starter reconnected, subscribing again");
+                return Ok(());
+            }
+            // android does not always close the stream on a disconnect
+            _ = sleep(Duration::from_secs(10)) => {
+                if !starter.is_connected().await.unwrap_or(false) {
+                    return Err(eyre!("Starter disconnected"));
+                }
+            }
+        }
+    }
+}
+
+/// Connects to a device found by a previous scan if it still offers `service`
+async fn connect_known(address: BDAddr, service: Uuid) -> Option<Peripheral> {
+    let adapter = btleplug::global_adapter();
+    let p = match adapter.add(address) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Failed to add known device {address}: {e}");
+            return None;
+        }
+    };
+    for i in 0..3 {
+        if p.connect().await.is_ok() {
+            let _ = p.discover_services().await;
+            if p.services().iter().any(|s| s.uuid == service) {
+                let _ = log_error(
+                    "Failed to authorize the bond",
+                    authorize(&p).await,
+                );
+                return Some(p);
+            }
+            warn!("Known device {address} does not offer {service}");
+            return None;
+        }
+        info!("Connecting to known device {address} failed ({i}), retry...");
+        sleep(Duration::from_secs(1)).await;
+    }
+    None
+}
+
+pub async fn search(adapter: &Adapter) -> color_eyre::Result<()> {
+    let mut known = KnownDevices::load().await;
     let mut found_starter = false;
     let mut found_door_controller = false;
-    let mut wait = 0;
-    'scan: loop {
-        if wait < 30 {
-            wait += 3;
+    if let Some(address) = known.starter {
+        if let Some(p) = connect_known(address, ENGINE_SERVICE_UUID).await {
+            info!("Connected to known starter {address}");
+            *STARTER.write().await = Some(p);
+            STARTER_GENERATION.send_modify(|generation| *generation += 1);
+            found_starter = true;
+        }
+    }
+    if let Some(address) = known.door_controller {
+        if let Some(p) = connect_known(address, DOOR_SERVICE_UUID).await {
+            info!("Connected to known door controller {address}");
+            *DOOR_CONTROLLER.write().await = Some(p);
+            found_door_controller = true;
         }
-        debug!("Searching...");
-        // give some time to scan
-        sleep(Duration::from_secs(wait)).await;
+    }
+    if found_starter && found_door_controller {
+        return Ok(());
+    }
+
+    let config = &config::get().ble;
+    let mut backoff = Duration::from_secs(config.scan_backoff_secs);
+    loop {
+        info!("Scanning for BLE devices");
+        adapter
+            .start_scan(ScanFilter {
+                services: vec![
+                    schema::DOOR_SERVICE_UUID,
+                    schema::ENGINE_SERVICE_UUID,
+                ],
+            })
+            .await?;
+        sleep(Duration::from_secs(config.scan_window_secs)).await;
         let peripherals = adapter.peripherals().await?;
-        // used to early exit the for each if both devices are found
+        adapter.stop_scan().await?;
         debug!("Total peripherals found: {}", peripherals.len());
 
-        'peripherals: for p in peripherals {
-            if found_starter && found_door_controller {
-                info!("Found both BLE devices");
-                adapter.stop_scan().await?;
-                break 'scan;
-            }
+        for p in peripherals {
+            let Some(properties) = p.properties().await? else {
+                continue;
+            };
+            let (device, service) = match identify(&properties) {
+                Some(Device::Starter) if !found_starter => {
+                    (Device::Starter, ENGINE_SERVICE_UUID)
+                }
+                Some(Device::DoorController) if !found_door_controller => {
+                    (Device::DoorController, DOOR_SERVICE_UUID)
+                }
+                _ => continue,
+            };
             if let Err(e) = p.connect().await {
-                warn!("Failed to connect to device {}: {e:?}", p.address())
+                warn!("Failed to connect to {device:?} {}: {e:?}", p.address());
+                continue;
             }
             if let Err(e) = p.discover_services().await {
                 warn!(
@@ -295,38 +750,149 @@ pub async fn search(adapter: &Adapter) -> color_eyre::Result<()> {
                         .collect::<BTreeSet<uuid::Uuid>>()
                 )
             }
-            for service in &p.services() {
-                if service.uuid == ENGINE_SERVICE_UUID && !found_starter {
+            if !p.services().iter().any(|s| s.uuid == service) {
+                warn!(
+                    "{} advertised but does not offer {service}",
+                    p.address()
+                );
+                let _ = p.disconnect().await;
+                continue;
+            }
+            let _ = log_error(
+                &format!("Failed to authorize the bond with {device:?}"),
+                authorize(&p).await,
+            );
+            match device {
+                Device::Starter => {
                     info!("Found starter with address {}", p.address());
-                    {
-                        let mut guard = STARTER.write().await;
-                        if guard.as_ref().is_none() {
-                            *guard = Some(p);
-                        } else {
-                            error!("BLE starter already initalized",);
-                        }
+                    known.starter = Some(p.address());
+                    known.save().await;
+                    let mut guard = STARTER.write().await;
+                    if guard.as_ref().is_none() {
+                        *guard = Some(p);
+                        STARTER_GENERATION
+                            .send_modify(|generation| *generation += 1);
+                    } else {
+                        error!("BLE starter already initalized",);
                     }
                     found_starter = true;
-                    continue 'peripherals;
-                } else if service.uuid == DOOR_SERVICE_UUID
-                    && !found_door_controller
-                {
+                }
+                Device::DoorController => {
                     info!("Found door controller with address {}", p.address());
-                    {
-                        let mut guard = DOOR_CONTROLLER.write().await;
-                        if guard.as_ref().is_none() {
-                            *guard = Some(p);
-                        } else {
-                            error!("BLE door controller already initalized")
-                        }
+                    known.door_controller = Some(p.address());
+                    known.save().await;
+                    let mut guard = DOOR_CONTROLLER.write().await;
+                    if guard.as_ref().is_none() {
+                        *guard = Some(p);
+                    } else {
+                        error!("BLE door controller already initalized")
                     }
                     found_door_controller = true;
-                    continue 'peripherals;
                 }
             }
         }
+        if found_starter && found_door_controller {
+            info!("Found both BLE devices");
+            return Ok(());
+        }
+        debug!("Scanning again in {backoff:?}");
+        sleep(backoff).await;
+        backoff = (backoff * 2)
+            .min(Duration::from_secs(config.max_scan_backoff_secs));
+    }
+}
+
+/// Pairs with a device that sends a challenge, until the bond is authorized
+///
+/// Reading the challenge makes the device request the pairing, it enters the
+/// passkey android displays once it got it sealed with [`PAIRING_KEY`].
+async fn authorize(peripheral: &Peripheral) -> color_eyre::Result<()> {
+    let Some(char) = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == schema::AUTH_CHAR)
+    else {
+        // firmware from before bonds had to be authorized
+        return Ok(());
+    };
+    let address = peripheral.address();
+    // subscribed first, android reports the passkey as soon as the pairing
+    // is requested
+    let mut passkeys = PASSKEYS.subscribe();
+    let challenge = peripheral.read(&char).await?;
+    if challenge.is_empty() {
+        return Ok(());
+    }
+    info!("Pairing with {address}");
+    let passkey = async {
+        loop {
+            match passkeys.recv().await {
+                Ok((from, passkey)) if from == address => return Some(passkey),
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    };
+    let passkey = timeout(PASSKEY_TIMEOUT, passkey)
+        .await
+        .ok()
+        .flatten()
+        .ok_or(eyre!("No passkey was displayed for {address}"))?;
+    let sealed = firmware_auth::seal_passkey(PAIRING_KEY, &challenge, passkey);
+    peripheral
+        .write(&char, &sealed, WriteType::WithResponse)
+        .await?;
+    // the challenge is cleared once the pairing completed
+    for _ in 0..AUTHORIZED_POLLS {
+        sleep(Duration::from_secs(1)).await;
+        if peripheral.read(&char).await?.is_empty() {
+            info!("Authorized the bond with {address}");
+            return Ok(());
+        }
+    }
+    Err(eyre!("{address} did not complete the pairing"))
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_erik_1tesar_car_remote_RustService_providePasskey(
+    env: JNIEnv,
+    _this: JClass,
+    address: JString,
+    passkey: jint,
+) {
+    let address: String = match env.get_string(address) {
+        Ok(o) => o.into(),
+        Err(e) => {
+            error!(
+                "Failed to get the address of the passkey from java: {e:#?}"
+            );
+            return;
+        }
+    };
+    let address = match address.parse::<BDAddr>() {
+        Ok(address) => address,
+        Err(e) => {
+            error!("Invalid address {address} of the passkey: {e}");
+            return;
+        }
+    };
+    // fails if no pairing is waiting for it, e.g. with other devices
+    let _ = PASSKEYS.send((address, passkey as u32));
+}
+
+/// Matches the advertisement against the services of our devices, so no
+/// connection to unrelated peripherals is made
+///
+/// The names are not checked because they can be changed in the device
+/// settings.
+fn identify(properties: &PeripheralProperties) -> Option<Device> {
+    if properties.services.contains(&ENGINE_SERVICE_UUID) {
+        Some(Device::Starter)
+    } else if properties.services.contains(&DOOR_SERVICE_UUID) {
+        Some(Device::DoorController)
+    } else {
+        None
     }
-    Ok(())
 }
 
 pub async fn try_reconnect_starter() -> bool {
@@ -355,13 +921,21 @@ pub async fn try_reconnect_starter() -> bool {
         for i in 0..10 {
             if new_starter.connect().await.is_ok() {
                 let _ = new_starter.discover_services().await;
+                let _ = log_error(
+                    "Failed to authorize the bond with the starter",
+                    authorize(&new_starter).await,
+                );
                 *guard = Some(new_starter);
+                STARTER_GENERATION.send_modify(|generation| *generation += 1);
+                health::record_reconnect(Device::Starter);
                 return true;
             } else {
                 info!("Reconnect to starter failed ({i}), retry...");
                 sleep(Duration::from_secs(1)).await;
             }
         }
+        // keep the address for the next attempt
+        *guard = Some(old_starter);
     }
     false
 }
@@ -393,13 +967,20 @@ pub async fn try_reconnect_door_controller() -> bool {
         for i in 0..10 {
             if new_door_controller.connect().await.is_ok() {
                 let _ = new_door_controller.discover_services().await;
+                let _ = log_error(
+                    "Failed to authorize the bond with the door controller",
+                    authorize(&new_door_controller).await,
+                );
                 *guard = Some(new_door_controller);
+                health::record_reconnect(Device::DoorController);
                 return true;
             } else {
                 info!("Reconnect to starter failed ({i}), retry...");
                 sleep(Duration::from_secs(1)).await;
             }
         }
+        // keep the address for the next attempt
+        *guard = Some(old_door_controller);
     }
     false
 }
@@ -0,0 +1,164 @@
+//! Compact binary command encoding
+//!
+//! A JWS with a JSON payload does not fit into a single GSM-7 SMS, which
+//! results in multipart messages that might arrive out of order. The compact
+//! form is the base32 (RFC 4648, no padding) encoding of
+//! `postcard(CompactCommand) || mac` where `mac` is the HMAC-SHA256 over
+//! [`MAC_CONTEXT`] followed by the postcard bytes, truncated to [`MAC_LEN`]
+//! bytes and keyed with the same secret as the JWT form. The context keeps a
+//! MAC of one form from being valid in the other. The result is 36 characters
+//! long.
+
+use color_eyre::eyre::eyre;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{schema::Command, sms::VerifiedCommand};
+
+/// Length of the truncated MAC in bytes
+pub const MAC_LEN: usize = 16;
+/// Prefix of the MAC input
+pub const MAC_CONTEXT: &[u8] = b"car-compact-v1";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct CompactCommand {
+    /// See [`Command::from_code`]
+    cmd: u8,
+    /// Unix timestamp in seconds
+    exp: u64,
+}
+
+/// JWTs always contain dots, the base32 alphabet does not
+pub fn is_compact(message: &str) -> bool {
+    !message.contains('.')
+}
+
+pub fn verify(
+    message: &str,
+    secret: &[u8],
+) -> color_eyre::Result<VerifiedCommand> {
+    let raw = BASE32_NOPAD
+        .decode(message.to_ascii_uppercase().as_bytes())
+        .map_err(|e| eyre!("Invalid compact encoding: {e}"))?;
+    if raw.len() <= MAC_LEN {
+        return Err(eyre!("Compact command is too short"));
+    }
+    let (payload, tag) = raw.split_at(raw.len() - MAC_LEN);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .map_err(|e| eyre!("Invalid key length: {e}"))?;
+    mac.update(MAC_CONTEXT);
+    mac.update(payload);
+    mac.verify_truncated_left(tag)
+        .map_err(|_| eyre!("Invalid compact command signature"))?;
+
+    // only a single encoding of a command is valid
+    let (compact, rest): (CompactCommand, _) =
+        postcard::take_from_bytes(payload)
+            .map_err(|e| eyre!("Invalid compact command: {e}"))?;
+    if !rest.is_empty() {
+        return Err(eyre!("Trailing bytes after the compact command"));
+    }
+    let command = Command::from_code(compact.cmd)
+        .ok_or(eyre!("Unknown command code {:#04x}", compact.cmd))?;
+    Ok(VerifiedCommand {
+        command,
+        expiration: compact.exp,
+        id: tag.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::BASE32_NOPAD;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{is_compact, verify, CompactCommand, MAC_CONTEXT, MAC_LEN};
+    use crate::schema::{Command, EngineCommand};
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn sign(mut raw: Vec<u8>, secret: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(MAC_CONTEXT);
+        mac.update(&raw);
+        raw.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+        BASE32_NOPAD.encode(&raw)
+    }
+
+    fn encode(cmd: u8, exp: u64, secret: &[u8]) -> String {
+        sign(
+            postcard::to_allocvec(&CompactCommand { cmd, exp }).unwrap(),
+            secret,
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = encode(0x02, 1_700_000_000, SECRET);
+        assert_eq!(message.len(), 36);
+        assert!(is_compact(&message));
+        let verified = verify(&message, SECRET).unwrap();
+        assert_eq!(verified.command, Command::Engine(EngineCommand::Engine));
+        assert_eq!(verified.expiration, 1_700_000_000);
+        assert_eq!(verified.id.len(), MAC_LEN);
+        // phones might change the case
+        assert!(verify(&message.to_ascii_lowercase(), SECRET).is_ok());
+    }
+
+    #[test]
+    fn expiration_is_signed() {
+        let message = encode(0x02, 1_700_000_000, SECRET);
+        let mut raw = BASE32_NOPAD.decode(message.as_bytes()).unwrap();
+        // the expiration follows the command byte
+        raw[1] ^= 0x01;
+        assert!(verify(&BASE32_NOPAD.encode(&raw), SECRET).is_err());
+    }
+
+    #[test]
+    fn corrupted_mac() {
+        let message = encode(0x02, 1_700_000_000, SECRET);
+        let mut raw = BASE32_NOPAD.decode(message.as_bytes()).unwrap();
+        *raw.last_mut().unwrap() ^= 0x01;
+        assert!(verify(&BASE32_NOPAD.encode(&raw), SECRET).is_err());
+        assert!(verify(&message, b"another secret").is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let message = encode(0x02, 1_700_000_000, SECRET);
+        let raw = BASE32_NOPAD.decode(message.as_bytes()).unwrap();
+        assert!(verify(&BASE32_NOPAD.encode(&raw[..raw.len() - 1]), SECRET)
+            .is_err());
+        assert!(verify(&BASE32_NOPAD.encode(&raw[..MAC_LEN]), SECRET).is_err());
+        assert!(verify("", SECRET).is_err());
+        assert!(verify("not base32!", SECRET).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut raw =
+            postcard::to_allocvec(&CompactCommand { cmd: 0x02, exp: 1 })
+                .unwrap();
+        raw.push(0);
+        assert!(verify(&sign(raw, SECRET), SECRET).is_err());
+    }
+
+    #[test]
+    fn without_context() {
+        let mut raw =
+            postcard::to_allocvec(&CompactCommand { cmd: 0x02, exp: 1 })
+                .unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(&raw);
+        raw.extend_from_slice(&mac.finalize().into_bytes()[..MAC_LEN]);
+        assert!(verify(&BASE32_NOPAD.encode(&raw), SECRET).is_err());
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert!(verify(&encode(0xff, 1_700_000_000, SECRET), SECRET).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{io::ErrorKind, sync::OnceLock};
+
+const CONFIG_PATH: &str = "/data/data/com.erik_tesar.car.remote/config.json";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings of the hub, loaded from [`CONFIG_PATH`]
+///
+/// Missing fields fall back to their default value, so the file only has to
+/// contain the settings that should differ.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Country calling code (e.g. `49`) assumed for phone numbers in national
+    /// format
+    pub default_country_code: String,
+    pub rate_limit: RateLimitConfig,
+    pub ble: BleConfig,
+    pub health: HealthConfig,
+    pub ota: OtaConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_country_code: "49".to_string(),
+            rate_limit: RateLimitConfig::default(),
+            ble: BleConfig::default(),
+            health: HealthConfig::default(),
+            ota: OtaConfig::default(),
+        }
+    }
+}
+
+/// Limits for incoming SMS, see [`crate::ratelimit::RateLimiter`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Burst of SMS accepted from all authorized numbers together
+    pub global_capacity: u32,
+    pub global_per_minute: u32,
+    /// Burst of SMS accepted from a single number
+    pub number_capacity: u32,
+    pub number_per_minute: u32,
+    /// Failed authentications of a number until it is locked out
+    pub max_failures: u32,
+    /// Duration of the first lockout, doubled on every following lockout
+    pub lockout_base_secs: u64,
+    pub lockout_max_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            global_capacity: 10,
+            global_per_minute: 10,
+            number_capacity: 5,
+            number_per_minute: 3,
+            max_failures: 5,
+            lockout_base_secs: 60,
+            lockout_max_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Thresholds of [`crate::health`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub sample_interval_secs: u64,
+    /// Signal strength below which a link is considered degraded
+    pub weak_rssi_dbm: i16,
+    /// Write latency above which a link is considered degraded
+    pub slow_write_ms: u64,
+    /// Duration a link has to be degraded before an alert is sent
+    pub alert_after_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            sample_interval_secs: 30,
+            weak_rssi_dbm: -90,
+            slow_write_ms: 2000,
+            alert_after_secs: 10 * 60,
+        }
+    }
+}
+
+/// Firmware updates, see [`crate::ota`]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct OtaConfig {
+    /// How often the firmware directory is checked for new images
+    pub poll_interval_secs: u64,
+    /// Bytes of the image per write, halved after every failed attempt
+    pub chunk_size: usize,
+    /// Failed upload attempts without progress until the update is given up
+    pub max_stalls: u32,
+    /// Updates of an image that failed this often are not retried
+    pub max_attempts: u32,
+    /// Time the device has to come back with the new image after a reboot
+    pub verify_timeout_secs: u64,
+}
+
+impl Default for OtaConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 60,
+            chunk_size: 240,
+            max_stalls: 10,
+            max_attempts: 3,
+            verify_timeout_secs: 2 * 60,
+        }
+    }
+}
+
+pub async fn init() {
+    let config = match tokio::fs::read(CONFIG_PATH).await {
+        Ok(buf) => match serde_json::from_slice(&buf) {
+            Ok(config) => {
+                info!("Loaded config: {config:?}");
+                config
+            }
+            Err(e) => {
+                error!("Invalid config, using defaults: {e}");
+                Config::default()
+            }
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            info!("No config found, using defaults");
+            Config::default()
+        }
+        Err(e) => {
+            error!("Failed to read config, using defaults: {e}");
+            Config::default()
+        }
+    };
+    let _ = CONFIG.set(config);
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Delivery of commands to the BLE devices
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct BleConfig {
+    /// Commands not executed within this time are abandoned
+    pub command_timeout_secs: u64,
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every following retry
+    pub retry_backoff_ms: u64,
+    pub max_retry_backoff_ms: u64,
+    /// Duration of a single scan for the devices
+    pub scan_window_secs: u64,
+    /// Pause after an unsuccessful scan, doubled after every further one
+    pub scan_backoff_secs: u64,
+    pub max_scan_backoff_secs: u64,
+    /// Engine states older than this are read again before relying on them
+    pub engine_state_max_age_secs: u64,
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        Self {
+            command_timeout_secs: 60,
+            max_retries: 5,
+            retry_backoff_ms: 1000,
+            max_retry_backoff_ms: 10_000,
+            scan_window_secs: 10,
+            scan_backoff_secs: 5,
+            max_scan_backoff_secs: 5 * 60,
+            engine_state_max_age_secs: 30,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Addresses of the starter and door controller found by a previous scan
+//!
+//! Both devices are bonded, so android reports their identity address even
+//! if they advertise with a resolvable private address. Connecting to the
+//! stored address directly avoids a full scan on every start.
+
+use std::io::ErrorKind;
+
+use btleplug::api::BDAddr;
+use jni::{objects::JClass, JNIEnv};
+
+const DEVICES_PATH: &str = "/data/data/com.erik_tesar.car.remote/devices.json";
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KnownDevices {
+    pub starter: Option<BDAddr>,
+    pub door_controller: Option<BDAddr>,
+}
+
+impl KnownDevices {
+    pub async fn load() -> Self {
+        match tokio::fs::read(DEVICES_PATH).await {
+            Ok(buf) => serde_json::from_slice(&buf).unwrap_or_else(|e| {
+                error!("Invalid known devices, scanning instead: {e}");
+                Self::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                error!("Failed to read known devices: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    pub async fn save(&self) {
+        let ser = match serde_json::to_vec(self) {
+            Ok(ser) => ser,
+            Err(e) => {
+                error!("Failed to serialize known devices: {e}");
+                return;
+            }
+        };
+        if let Err(e) = tokio::fs::write(DEVICES_PATH, ser).await {
+            error!("Failed to persist known devices: {e}");
+        }
+    }
+}
+
+/// Forgets the stored addresses, the next start scans for the devices again
+#[no_mangle]
+pub extern "system" fn Java_com_erik_1tesar_car_remote_MainActivity_forgetDevices(
+    _env: JNIEnv,
+    _this: JClass,
+) {
+    match std::fs::remove_file(DEVICES_PATH) {
+        Ok(()) => info!("Forgot known devices"),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to forget known devices: {e}"),
+    }
+}
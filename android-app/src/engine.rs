@@ -0,0 +1,208 @@
+//! Engine state of the starter as known by the hub
+//!
+//! The state carries where it came from and when, so decisions can tell a
+//! value read a second ago from one that was assumed hours ago. Tasks that
+//! need the door controller powered share a single [`EngineHold`].
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc::UnboundedSender, Mutex, RwLock};
+
+use crate::{
+    ble::{self, BleRequest},
+    config, log_error,
+    schema::{Command, EngineCommand},
+};
+
+static ENGINE_STATUS: RwLock<EngineStatus> =
+    RwLock::const_new(EngineStatus::Unknown);
+static ENGINE_HOLDERS: Mutex<Holders> = Mutex::const_new(Holders {
+    count: 0,
+    restore: None,
+});
+
+/// Tasks holding the engine on and the state from before the first of them
+struct Holders {
+    count: usize,
+    restore: Option<EngineCommand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineSource {
+    /// Notified by the starter
+    Notification,
+    /// Read from the starter
+    Read,
+    /// Written by the hub, not yet confirmed by the starter
+    Assumed,
+}
+
+#[derive(Debug, Clone)]
+pub enum EngineStatus {
+    Unknown,
+    Known {
+        state: EngineCommand,
+        source: EngineSource,
+        at: Instant,
+    },
+}
+
+impl EngineStatus {
+    /// The state if it is confirmed by the starter and recent enough to
+    /// base a decision on
+    pub fn fresh(&self) -> Option<EngineCommand> {
+        let max_age =
+            Duration::from_secs(config::get().ble.engine_state_max_age_secs);
+        match self {
+            Self::Known { state, source, at }
+                if *source != EngineSource::Assumed
+                    && at.elapsed() <= max_age =>
+            {
+                Some(state.to_owned())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub async fn get() -> EngineStatus {
+    ENGINE_STATUS.read().await.to_owned()
+}
+
+pub async fn set(state: EngineCommand, source: EngineSource) {
+    debug!("Engine state {state:?} ({source:?})");
+    *ENGINE_STATUS.write().await = EngineStatus::Known {
+        state,
+        source,
+        at: Instant::now(),
+    };
+}
+
+pub async fn set_unknown() {
+    *ENGINE_STATUS.write().await = EngineStatus::Unknown;
+}
+
+/// Returns a fresh engine state, reading it from the starter if the known
+/// one is stale
+pub async fn current() -> color_eyre::Result<EngineCommand> {
+    if let Some(state) = get().await.fresh() {
+        return Ok(state);
+    }
+    info!("Engine state is stale, reading it from the starter");
+    ble::read_engine_state().await
+}
+
+/// Keeps the engine on until released, the door controller is only powered
+/// while it is
+///
+/// The state from before the first hold is restored once the last holder
+/// released it, so overlapping holders cannot switch the engine off under
+/// each other. Dropping a hold without [`EngineHold::release`] still releases
+/// it, in the background.
+pub struct EngineHold {
+    ble_sender: UnboundedSender<BleRequest>,
+    released: bool,
+}
+
+impl EngineHold {
+    /// Switches the engine on unless it is already on or held
+    pub async fn acquire(
+        ble_sender: &UnboundedSender<BleRequest>,
+    ) -> color_eyre::Result<Self> {
+        let mut holders = ENGINE_HOLDERS.lock().await;
+        if holders.count == 0 {
+            // restoring a stale state could switch the engine to something
+            // nobody asked for
+            let state = current().await?;
+            if !matches!(state, EngineCommand::Engine | EngineCommand::Ignition)
+            {
+                info!("Holding engine for the door controller");
+                ble::send(ble_sender, Command::Engine(EngineCommand::Engine))
+                    .await?;
+                holders.restore = Some(state);
+            }
+        }
+        holders.count += 1;
+        Ok(Self {
+            ble_sender: ble_sender.to_owned(),
+            released: false,
+        })
+    }
+
+    /// Restores the engine state if this was the last holder
+    pub async fn release(mut self) -> color_eyre::Result<()> {
+        self.released = true;
+        release(&self.ble_sender).await
+    }
+}
+
+impl Drop for EngineHold {
+    fn drop(&mut self) {
+        if !self.released {
+            let ble_sender = self.ble_sender.to_owned();
+            tokio::spawn(async move {
+                let _ = log_error(
+                    "Failed to release the engine",
+                    release(&ble_sender).await,
+                );
+            });
+        }
+    }
+}
+
+async fn release(
+    ble_sender: &UnboundedSender<BleRequest>,
+) -> color_eyre::Result<()> {
+    let mut holders = ENGINE_HOLDERS.lock().await;
+    holders.count -= 1;
+    if holders.count > 0 {
+        return Ok(());
+    }
+    let Some(state) = holders.restore.take() else {
+        return Ok(());
+    };
+    info!("Restoring engine state to {state:?}");
+    ble::send(ble_sender, Command::Engine(state)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{set, EngineHold, EngineSource};
+    use crate::{
+        ble::BleRequest,
+        schema::{Command, EngineCommand},
+    };
+
+    #[tokio::test]
+    async fn restores_after_last_hold() {
+        let (ble_sender, mut requests) =
+            mpsc::unbounded_channel::<BleRequest>();
+        let sent = tokio::spawn(async move {
+            let mut sent = Vec::new();
+            while let Some(request) = requests.recv().await {
+                sent.push(request.command.to_owned());
+                let _ = request.reply.send(Ok(()));
+            }
+            sent
+        });
+        set(EngineCommand::Off, EngineSource::Read).await;
+
+        let first = EngineHold::acquire(&ble_sender).await.unwrap();
+        let second = EngineHold::acquire(&ble_sender).await.unwrap();
+        // the second holder still needs the engine
+        first.release().await.unwrap();
+        second.release().await.unwrap();
+        drop(ble_sender);
+
+        let sent = sent.await.unwrap();
+        assert!(matches!(
+            sent.as_slice(),
+            [
+                Command::Engine(EngineCommand::Engine),
+                Command::Engine(EngineCommand::Off)
+            ]
+        ));
+    }
+}
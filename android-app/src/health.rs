@@ -0,0 +1,262 @@
+//! Link quality of the BLE devices
+//!
+//! Samples the connection state and the RSSI of connected devices periodically
+//! and collects reconnects and write latencies from the BLE handlers. If a
+//! device stays degraded for [`HealthConfig::alert_after_secs`] an alert SMS
+//! is sent once. Panics the devices recorded before their last reset are
+//! fetched once per connection and sent as an alert too.
+
+use std::{
+    convert::Infallible,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use btleplug::{api::Peripheral as _, platform::Peripheral};
+use tokio::{sync::RwLock, task::spawn_blocking, time::sleep};
+
+use crate::{
+    ble::{self, DOOR_CONTROLLER, STARTER},
+    config::{self, HealthConfig},
+    queue::Device,
+    sms,
+};
+
+static STARTER_HEALTH: Mutex<DeviceHealth> = Mutex::new(DeviceHealth::new());
+static DOOR_CONTROLLER_HEALTH: Mutex<DeviceHealth> =
+    Mutex::new(DeviceHealth::new());
+
+#[derive(Debug, Clone)]
+pub struct DeviceHealth {
+    pub connected: bool,
+    /// Signal strength in dBm, read from the connection on every sample
+    pub rssi: Option<i16>,
+    pub connected_since: Option<Instant>,
+    /// Reconnects since the hub was started
+    pub reconnects: u32,
+    pub last_write_latency: Option<Duration>,
+    /// When the last write finished, older writes do not degrade the link
+    last_write_at: Option<Instant>,
+    /// Panic the device reported since the hub was started
+    pub last_panic: Option<String>,
+    degraded_since: Option<Instant>,
+    alerted: bool,
+    /// Whether the panic record was fetched on the current connection
+    panic_checked: bool,
+}
+
+impl DeviceHealth {
+    const fn new() -> Self {
+        Self {
+            connected: false,
+            rssi: None,
+            connected_since: None,
+            reconnects: 0,
+            last_write_latency: None,
+            last_write_at: None,
+            last_panic: None,
+            degraded_since: None,
+            alerted: false,
+            panic_checked: false,
+        }
+    }
+
+    /// Reason why the link is considered degraded
+    fn degradation(
+        &self,
+        device: Device,
+        config: &HealthConfig,
+    ) -> Option<String> {
+        // the door controller is only powered while the engine is running
+        if !self.connected && device == Device::Starter {
+            return Some("disconnected".to_string());
+        }
+        if let Some(rssi) =
+            self.rssi.filter(|rssi| *rssi < config.weak_rssi_dbm)
+        {
+            return Some(format!("weak signal ({rssi} dBm)"));
+        }
+        // a single slow write only counts until the next sample
+        let recent = self.last_write_at.is_some_and(|at| {
+            at.elapsed() <= Duration::from_secs(config.sample_interval_secs)
+        });
+        if let Some(latency) = self.last_write_latency.filter(|l| {
+            recent && *l > Duration::from_millis(config.slow_write_ms)
+        }) {
+            return Some(format!("slow writes ({} ms)", latency.as_millis()));
+        }
+        None
+    }
+}
+
+fn health(device: Device) -> &'static Mutex<DeviceHealth> {
+    match device {
+        Device::Starter => &STARTER_HEALTH,
+        Device::DoorController => &DOOR_CONTROLLER_HEALTH,
+    }
+}
+
+pub fn get(device: Device) -> DeviceHealth {
+    health(device)
+        .lock()
+        .expect("health lock poisoned")
+        .to_owned()
+}
+
+pub fn record_reconnect(device: Device) {
+    let mut health = health(device).lock().expect("health lock poisoned");
+    health.reconnects += 1;
+    health.connected_since = Some(Instant::now());
+    health.panic_checked = false;
+}
+
+pub fn record_write(device: Device, latency: Duration) {
+    let mut health = health(device).lock().expect("health lock poisoned");
+    health.last_write_latency = Some(latency);
+    health.last_write_at = Some(Instant::now());
+}
+
+async fn sample(device: Device, peripheral: &RwLock<Option<Peripheral>>) {
+    let address = match peripheral.read().await.as_ref() {
+        Some(p) if p.is_connected().await.unwrap_or(false) => Some(p.address()),
+        _ => None,
+    };
+    let connected = address.is_some();
+    let rssi = match address {
+        // blocks until android answers, which takes a connection event
+        Some(address) => match spawn_blocking(move || sms::read_rssi(address))
+            .await
+            .map_err(Into::into)
+            .and_then(|rssi| rssi)
+        {
+            Ok(rssi) => rssi,
+            Err(e) => {
+                warn!("Failed to read the RSSI of {device:?}: {e}");
+                None
+            }
+        },
+        None => None,
+    };
+    let config = &config::get().health;
+    let alert = {
+        let mut health = health(device).lock().expect("health lock poisoned");
+        health.connected = connected;
+        health.rssi = rssi;
+        match (connected, health.connected_since) {
+            (true, None) => health.connected_since = Some(Instant::now()),
+            (false, _) => {
+                health.connected_since = None;
+                health.panic_checked = false;
+            }
+            _ => {}
+        }
+        match health.degradation(device, config) {
+            Some(reason) => {
+                let since =
+                    *health.degraded_since.get_or_insert(Instant::now());
+                if !health.alerted
+                    && since.elapsed()
+                        >= Duration::from_secs(config.alert_after_secs)
+                {
+                    health.alerted = true;
+                    Some(reason)
+                } else {
+                    None
+                }
+            }
+            None => {
+                health.degraded_since = None;
+                health.alerted = false;
+                None
+            }
+        }
+    };
+    if let Some(reason) = alert {
+        sms::alert(&format!("Car: {device:?} link degraded, {reason}"));
+    }
+    if connected && !get(device).panic_checked {
+        check_panic(device).await;
+    }
+}
+
+async fn check_panic(device: Device) {
+    let record = match ble::take_panic_record(device).await {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Failed to read panic record of {device:?}: {e}");
+            return;
+        }
+    };
+    {
+        let mut health = health(device).lock().expect("health lock poisoned");
+        health.panic_checked = true;
+        if let Some(record) = &record {
+            health.last_panic = Some(record.to_owned());
+        }
+    }
+    if let Some(record) = record {
+        error!("{device:?} panicked before its last reset: {record}");
+        sms::alert(&format!("Car: {device:?} crashed, {record}"));
+    }
+}
+
+pub async fn monitor() -> color_eyre::Result<Infallible> {
+    let interval =
+        Duration::from_secs(config::get().health.sample_interval_secs);
+    loop {
+        sample(Device::Starter, &STARTER).await;
+        sample(Device::DoorController, &DOOR_CONTROLLER).await;
+        sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::DeviceHealth;
+    use crate::{config::HealthConfig, queue::Device};
+
+    fn connected() -> DeviceHealth {
+        DeviceHealth {
+            connected: true,
+            ..DeviceHealth::new()
+        }
+    }
+
+    #[test]
+    fn weak_signal() {
+        let config = HealthConfig::default();
+        let mut health = connected();
+        health.rssi = Some(config.weak_rssi_dbm - 1);
+        assert!(health.degradation(Device::Starter, &config).is_some());
+        health.rssi = Some(config.weak_rssi_dbm);
+        assert!(health.degradation(Device::Starter, &config).is_none());
+    }
+
+    #[test]
+    fn fast_write_after_slow() {
+        let config = HealthConfig::default();
+        let slow = Duration::from_millis(config.slow_write_ms + 1);
+        let mut health = connected();
+        health.last_write_latency = Some(slow);
+        health.last_write_at = Some(Instant::now());
+        assert!(health.degradation(Device::Starter, &config).is_some());
+        health.last_write_latency = Some(Duration::from_millis(10));
+        health.last_write_at = Some(Instant::now());
+        assert!(health.degradation(Device::Starter, &config).is_none());
+    }
+
+    #[test]
+    fn slow_write_ages() {
+        let config = HealthConfig::default();
+        let mut health = connected();
+        health.last_write_latency =
+            Some(Duration::from_millis(config.slow_write_ms + 1));
+        health.last_write_at = Some(
+            Instant::now()
+                - Duration::from_secs(config.sample_interval_secs + 1),
+        );
+        assert!(health.degradation(Device::Starter, &config).is_none());
+    }
+}
@@ -6,9 +6,21 @@ use jni_utils as _;
 #[macro_use]
 extern crate log;
 
+mod audit;
 mod ble;
+mod compact;
+mod config;
+mod devices;
+mod engine;
+mod health;
+mod ota;
+mod phone;
+mod queue;
+mod ratelimit;
+mod replay;
 mod schema;
 mod sms;
+mod status;
 
 #[allow(non_snake_case)]
 pub mod android {
@@ -43,8 +55,19 @@ pub mod android {
 #[tokio::main(flavor = "current_thread")]
 async fn launch(env: JNIEnv<'_>) -> color_eyre::Result<Infallible> {
     info!("Launched tokio!");
+    status::init();
+    config::init().await;
     let (ble_sender, search, listen, update, events) = ble::init(&env).await?;
-    let sms = sms::init(ble_sender).await?;
+    let ota = tokio::spawn({
+        let ble_sender = ble_sender.clone();
+        async {
+            log_error("Firmware updates failed", ota::run(ble_sender).await)
+        }
+    });
+    let sms = sms::init(&env, ble_sender).await?;
+    let health = tokio::spawn(async {
+        log_error("Health monitor failed", health::monitor().await)
+    });
 
     let e = tokio::select! {
         Err(e) = search => {
@@ -68,6 +91,14 @@ async fn launch(env: JNIEnv<'_>) -> color_eyre::Result<Infallible> {
             error!("Event error: {e:#?}");
             e
         },
+        Err(e) = health => {
+            error!("Health error: {e:#?}");
+            e
+        },
+        Err(e) = ota => {
+            error!("Firmware update error: {e:#?}");
+            e
+        },
     };
     Err(e.into())
 }
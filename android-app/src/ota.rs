@@ -0,0 +1,357 @@
+//! Firmware updates of the BLE devices
+//!
+//! Signed images created by `sign-image` are picked up from [`FIRMWARE_DIR`]
+//! as `<device>.fw`, see the README.
+//! Every chunk is written with response, so the device acknowledges it before
+//! the next one is sent, and an interrupted upload is resumed at the offset
+//! the device reports. An update only counts as done once the device runs the
+//! new image and confirmed it.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use btleplug::{
+    api::{Characteristic, Peripheral as _, WriteType},
+    platform::Peripheral,
+};
+use color_eyre::eyre::eyre;
+use firmware_image::{Header, Target, HEADER_LEN};
+use tokio::{
+    sync::mpsc::UnboundedSender,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    ble::{self, BleRequest},
+    config,
+    engine::{self, EngineHold},
+    log_error,
+    queue::{self, Device},
+    schema::{self, EngineCommand, OtaStatus},
+    sms,
+};
+
+const FIRMWARE_DIR: &str = "/data/data/com.erik_tesar.car.remote/firmware";
+
+const BEGIN: u8 = 1;
+const FINISH: u8 = 2;
+/// Offset in front of every chunk
+const DATA_HEADER_LEN: usize = 4;
+/// Largest chunk the firmwares accept, chunks have to be a multiple of 4
+/// bytes
+const MAX_CHUNK_SIZE: usize = 248;
+const MIN_CHUNK_SIZE: usize = 16;
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+static LAST_UPDATE: Mutex<Option<UpdateOutcome>> = Mutex::new(None);
+
+/// Update that is currently running
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub device: Device,
+    /// Bytes acknowledged by the device
+    pub written: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateOutcome {
+    pub device: Device,
+    pub at: SystemTime,
+    pub result: Result<(), String>,
+}
+
+pub fn progress() -> Option<Progress> {
+    PROGRESS.lock().expect("progress lock poisoned").to_owned()
+}
+
+pub fn last_update() -> Option<UpdateOutcome> {
+    LAST_UPDATE.lock().expect("update lock poisoned").to_owned()
+}
+
+fn set_progress(progress: Option<Progress>) {
+    *PROGRESS.lock().expect("progress lock poisoned") = progress;
+}
+
+struct Image {
+    header: Header,
+    /// App image without the header
+    data: Vec<u8>,
+}
+
+fn image_path(device: Device) -> PathBuf {
+    let name = match device {
+        Device::Starter => "starter",
+        Device::DoorController => "door-controller",
+    };
+    Path::new(FIRMWARE_DIR).join(name).with_extension("fw")
+}
+
+async fn load(device: Device) -> color_eyre::Result<Option<Image>> {
+    let mut file = match tokio::fs::read(image_path(device)).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let header = Header::parse(&file)
+        .map_err(|e| eyre!("Invalid {device:?} image header: {e:?}"))?;
+    let target = match device {
+        Device::Starter => Target::Starter,
+        Device::DoorController => Target::DoorController,
+    };
+    if header.target != target {
+        return Err(eyre!("{device:?} image is built for {:?}", header.target));
+    }
+    // the signature is checked by the device
+    let data = file.split_off(HEADER_LEN);
+    if !header.matches(&data) {
+        return Err(eyre!("{device:?} image does not match its header"));
+    }
+    Ok(Some(Image { header, data }))
+}
+
+/// Removes the image so it is not installed again
+async fn discard(device: Device, installed: bool) {
+    let path = image_path(device);
+    let res = if installed {
+        tokio::fs::remove_file(&path).await
+    } else {
+        // keep a failed image around for debugging
+        tokio::fs::rename(&path, path.with_extension("fw.failed")).await
+    };
+    if let Err(e) = res {
+        error!("Failed to discard {device:?} image: {e}");
+    }
+}
+
+/// Checks [`FIRMWARE_DIR`] for new images and installs them
+pub async fn run(
+    ble_sender: UnboundedSender<BleRequest>,
+) -> color_eyre::Result<Infallible> {
+    let config = &config::get().ota;
+    // failed attempts per image hash
+    let mut attempts: BTreeMap<[u8; 32], u32> = BTreeMap::new();
+    loop {
+        sleep(Duration::from_secs(config.poll_interval_secs)).await;
+        for device in [Device::Starter, Device::DoorController] {
+            let image = match load(device).await {
+                Ok(Some(image)) => image,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to load {device:?} image: {e}");
+                    continue;
+                }
+            };
+            // the reboot would interrupt the relays
+            if device == Device::Starter
+                && !matches!(engine::current().await, Ok(EngineCommand::Off))
+            {
+                info!("Postponing starter update until the engine is off");
+                continue;
+            }
+            let version = image.header.version;
+            info!("Updating {device:?} to version {version}");
+            let res = log_error(
+                "Firmware update failed",
+                update(&ble_sender, device, &image).await,
+            );
+            set_progress(None);
+            let failed = attempts.entry(image.header.digest).or_default();
+            match &res {
+                Ok(()) => {
+                    discard(device, true).await;
+                    sms::alert(&format!(
+                        "Car: {device:?} updated to version {version}"
+                    ));
+                }
+                Err(e) => {
+                    *failed += 1;
+                    if *failed >= config.max_attempts {
+                        discard(device, false).await;
+                        sms::alert(&format!(
+                            "Car: {device:?} update to version {version} failed: {e}"
+                        ));
+                    }
+                }
+            }
+            *LAST_UPDATE.lock().expect("update lock poisoned") =
+                Some(UpdateOutcome {
+                    device,
+                    at: SystemTime::now(),
+                    result: res.map_err(|e| e.to_string()),
+                });
+        }
+    }
+}
+
+async fn update(
+    ble_sender: &UnboundedSender<BleRequest>,
+    device: Device,
+    image: &Image,
+) -> color_eyre::Result<()> {
+    // the door controller is only powered while the engine is on
+    let hold = match device {
+        Device::DoorController => Some(EngineHold::acquire(ble_sender).await?),
+        Device::Starter => None,
+    };
+    let res = async {
+        // commands must not interleave with the chunks, they are executed
+        // once the device runs the new image
+        let _paused = queue::pause(device).await;
+        upload(device, image).await?;
+        verify(device, image).await
+    }
+    .await;
+    if let Some(hold) = hold {
+        hold.release().await?;
+    }
+    res
+}
+
+struct OtaChars {
+    control: Characteristic,
+    data: Characteristic,
+    status: Characteristic,
+}
+
+impl OtaChars {
+    fn find(
+        device: Device,
+        peripheral: &Peripheral,
+    ) -> color_eyre::Result<Self> {
+        let characteristics = peripheral.characteristics();
+        let find = |uuid| {
+            characteristics
+                .iter()
+                .find(|c| c.uuid == uuid)
+                .cloned()
+                .ok_or(eyre!("{device:?} does not support updates"))
+        };
+        Ok(Self {
+            control: find(schema::OTA_CONTROL_CHAR)?,
+            data: find(schema::OTA_DATA_CHAR)?,
+            status: find(schema::OTA_STATUS_CHAR)?,
+        })
+    }
+}
+
+async fn read_status(
+    peripheral: &Peripheral,
+    chars: &OtaChars,
+) -> color_eyre::Result<OtaStatus> {
+    let value = peripheral.read(&chars.status).await?;
+    postcard::from_bytes(&value).map_err(|e| eyre!("Invalid OTA status: {e}"))
+}
+
+/// Uploads the image, retrying with smaller chunks as long as the device
+/// makes progress
+async fn upload(device: Device, image: &Image) -> color_eyre::Result<()> {
+    let config = &config::get().ota;
+    let mut chunk_size =
+        config.chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) / 4 * 4;
+    let mut stalls = 0;
+    let mut written = 0;
+    loop {
+        let before = written;
+        match upload_attempt(device, image, chunk_size, &mut written).await {
+            Ok(()) => return Ok(()),
+            Err(e) if stalls < config.max_stalls => {
+                if written > before {
+                    stalls = 0;
+                }
+                stalls += 1;
+                chunk_size = (chunk_size / 2 / 4 * 4).max(MIN_CHUNK_SIZE);
+                warn!(
+                    "Update of {device:?} interrupted at {written}, retry {stalls} with {chunk_size} byte chunks: {e}"
+                );
+                sleep(Duration::from_secs(2)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn upload_attempt(
+    device: Device,
+    image: &Image,
+    chunk_size: usize,
+    written: &mut usize,
+) -> color_eyre::Result<()> {
+    let peripheral = ble::connected(device).await?;
+    let chars = OtaChars::find(device, &peripheral)?;
+    let size = image.data.len();
+    let mut begin = vec![BEGIN];
+    begin.extend(image.header.to_bytes());
+    peripheral
+        .write(&chars.control, &begin, WriteType::WithResponse)
+        .await?;
+    // the device continues an interrupted upload of the same image
+    let mut offset = read_status(&peripheral, &chars).await?.offset as usize;
+    if offset != *written {
+        info!("Continuing update of {device:?} at {offset}");
+    }
+    while offset < size {
+        let end = (offset + chunk_size).min(size);
+        let mut chunk = Vec::with_capacity(DATA_HEADER_LEN + end - offset);
+        chunk.extend((offset as u32).to_le_bytes());
+        chunk.extend(&image.data[offset..end]);
+        peripheral
+            .write(&chars.data, &chunk, WriteType::WithResponse)
+            .await?;
+        offset = end;
+        *written = offset;
+        set_progress(Some(Progress {
+            device,
+            written: offset as u32,
+            size: size as u32,
+        }));
+    }
+    info!("Uploaded {size} bytes to {device:?}, activating the image");
+    peripheral
+        .write(&chars.control, &[FINISH], WriteType::WithResponse)
+        .await?;
+    Ok(())
+}
+
+/// Waits until the device rebooted into the new image and confirmed it
+async fn verify(device: Device, image: &Image) -> color_eyre::Result<()> {
+    let timeout = Duration::from_secs(config::get().ota.verify_timeout_secs);
+    let deadline = Instant::now() + timeout;
+    let mut last = eyre!("{device:?} did not come back");
+    while Instant::now() < deadline {
+        sleep(Duration::from_secs(5)).await;
+        let status = async {
+            let peripheral = ble::connected(device).await?;
+            let chars = OtaChars::find(device, &peripheral)?;
+            read_status(&peripheral, &chars).await
+        }
+        .await;
+        match status {
+            Ok(status) if status.running_hash != image.header.digest[..20] => {
+                // also the case if it was rolled back
+                last =
+                    eyre!("{device:?} runs {}", hex(&status.running_hash[..4]));
+            }
+            // reading the status confirms the image, check again
+            Ok(status) if !status.confirmed => {
+                last = eyre!("{device:?} did not confirm the new image");
+            }
+            Ok(_) => {
+                info!("{device:?} runs the new image");
+                return Ok(());
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,83 @@
+/// Numbers up to this length are treated as short codes which have no
+/// international representation
+const MAX_SHORT_CODE_LEN: usize = 6;
+
+/// Normalizes a phone number to E.164 (`+<country code><subscriber number>`)
+///
+/// Numbers in national format (leading `0`) get the `default_country_code`
+/// prepended. Longer numbers without a leading `0` or `+` either already
+/// start with the `default_country_code` (the `+` was dropped) and get the `+`
+/// back, or are national numbers of a country without trunk prefix and get
+/// the `default_country_code` prepended. Short codes and alphanumeric senders
+/// cannot be normalized and are only stripped of formatting characters.
+pub fn normalize(number: &str, default_country_code: &str) -> String {
+    // german style "+49 (0)151 ..." where the trunk prefix must be dropped
+    let number = number.trim().replace("(0)", "");
+    let number: String = number
+        .chars()
+        .filter(|c| {
+            !matches!(c, '-' | '(' | ')' | '/' | '.') && !c.is_whitespace()
+        })
+        .collect();
+
+    let digits = number.strip_prefix('+').unwrap_or(&number);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return number;
+    }
+
+    let country_code = default_country_code.trim().trim_start_matches('+');
+    if number.starts_with('+') {
+        number
+    } else if let Some(international) = number.strip_prefix("00") {
+        format!("+{international}")
+    } else if number.len() <= MAX_SHORT_CODE_LEN {
+        number
+    } else if let Some(national) = number.strip_prefix('0') {
+        format!("+{country_code}{national}")
+    } else if !country_code.is_empty() && number.starts_with(country_code) {
+        format!("+{number}")
+    } else {
+        format!("+{country_code}{number}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn national() {
+        assert_eq!(normalize("0151 1234567", "49"), "+491511234567");
+        assert_eq!(normalize("0151/123-45-67", "+49"), "+491511234567");
+        assert_eq!(normalize("(0151) 1234567", "49"), "+491511234567");
+    }
+
+    #[test]
+    fn international() {
+        assert_eq!(normalize("+49 151 1234567", "49"), "+491511234567");
+        assert_eq!(normalize("+49 (0)151 1234567", "49"), "+491511234567");
+        assert_eq!(normalize("0049 151 1234567", "49"), "+491511234567");
+        assert_eq!(normalize("+33 6 12 34 56 78", "49"), "+33612345678");
+    }
+
+    #[test]
+    fn without_prefix() {
+        // country code with the `+` dropped
+        assert_eq!(normalize("491511234567", "49"), "+491511234567");
+        // national number of a country without trunk prefix
+        assert_eq!(normalize("202 555 0123", "1"), "+12025550123");
+    }
+
+    #[test]
+    fn short_codes() {
+        assert_eq!(normalize("22222", "49"), "22222");
+        assert_eq!(normalize("112", "49"), "112");
+        assert_eq!(normalize("0800", "49"), "0800");
+    }
+
+    #[test]
+    fn alphanumeric() {
+        assert_eq!(normalize("Vodafone", "49"), "Vodafone");
+        assert_eq!(normalize("", "49"), "");
+    }
+}
@@ -0,0 +1,189 @@
+//! Pending BLE commands per device
+//!
+//! Commands that write the same characteristic supersede each other, so a
+//! reconnecting device only receives the latest one instead of a burst of
+//! outdated commands. Because of that the queue holds at most one command per
+//! characteristic. Commands past their deadline are dropped.
+//!
+//! A firmware update pauses the queue of its device with [`pause`], so that
+//! commands do not interleave with the upload on the same GATT client.
+
+use std::{sync::Mutex, time::Duration};
+
+use color_eyre::eyre::eyre;
+use tokio::{
+    sync::{Mutex as AsyncMutex, MutexGuard, Notify},
+    time::Instant,
+};
+
+use crate::{
+    ble::BleRequest,
+    schema::{Command, DoorControllerCommand},
+};
+
+static QUEUE: Mutex<Vec<BleRequest>> = Mutex::new(Vec::new());
+static STARTER_READY: Notify = Notify::const_new();
+static DOOR_CONTROLLER_READY: Notify = Notify::const_new();
+static STARTER_LINK: AsyncMutex<()> = AsyncMutex::const_new(());
+static DOOR_CONTROLLER_LINK: AsyncMutex<()> = AsyncMutex::const_new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Starter,
+    DoorController,
+}
+
+impl Device {
+    fn ready(&self) -> &'static Notify {
+        match self {
+            Self::Starter => &STARTER_READY,
+            Self::DoorController => &DOOR_CONTROLLER_READY,
+        }
+    }
+
+    /// Held while a command or an update uses the GATT client of the device
+    fn link(&self) -> &'static AsyncMutex<()> {
+        match self {
+            Self::Starter => &STARTER_LINK,
+            Self::DoorController => &DOOR_CONTROLLER_LINK,
+        }
+    }
+}
+
+/// Characteristic written by a command, later commands for the same slot
+/// supersede earlier ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Engine,
+    Lock,
+    WindowLeft,
+    WindowRight,
+}
+
+impl Slot {
+    fn of(command: &Command) -> Option<Self> {
+        Some(match command {
+            Command::Engine(_) => Self::Engine,
+            Command::DoorController(
+                DoorControllerCommand::Lock | DoorControllerCommand::Unlock,
+            ) => Self::Lock,
+            Command::DoorController(
+                DoorControllerCommand::WindowLeftUp
+                | DoorControllerCommand::WindowLeftDown,
+            ) => Self::WindowLeft,
+            Command::DoorController(
+                DoorControllerCommand::WindowRightUp
+                | DoorControllerCommand::WindowRightDown,
+            ) => Self::WindowRight,
+            Command::Query(_) => return None,
+        })
+    }
+
+    fn device(&self) -> Device {
+        match self {
+            Self::Engine => Device::Starter,
+            Self::Lock | Self::WindowLeft | Self::WindowRight => {
+                Device::DoorController
+            }
+        }
+    }
+}
+
+fn reject(request: BleRequest, reason: color_eyre::Report) {
+    warn!("Dropping command {}: {reason}", request.id);
+    // the caller might not be interested in the result
+    let _ = request.reply.send(Err(reason));
+}
+
+/// Queues the request, superseding a pending command for the same
+/// characteristic
+pub fn push(request: BleRequest) {
+    let Some(slot) = Slot::of(&request.command) else {
+        reject(request, eyre!("Queries cannot be sent via BLE"));
+        return;
+    };
+    let mut queue = QUEUE.lock().expect("queue lock poisoned");
+    if let Some(index) = queue
+        .iter()
+        .position(|queued| Slot::of(&queued.command) == Some(slot))
+    {
+        let superseded = queue.remove(index);
+        let reason = eyre!(
+            "{:?} superseded by {:?} ({})",
+            superseded.command,
+            request.command,
+            request.id
+        );
+        reject(superseded, reason);
+    }
+    queue.push(request);
+    drop(queue);
+    slot.device().ready().notify_one();
+}
+
+fn pop(device: Device) -> Option<BleRequest> {
+    let mut queue = QUEUE.lock().expect("queue lock poisoned");
+    let now = Instant::now();
+    let (expired, pending): (Vec<_>, Vec<_>) =
+        queue.drain(..).partition(|request| request.deadline <= now);
+    *queue = pending;
+    // oldest command first
+    let next = queue
+        .iter()
+        .enumerate()
+        .filter(|(_, request)| {
+            Slot::of(&request.command).map(|slot| slot.device()) == Some(device)
+        })
+        .min_by_key(|(_, request)| request.id)
+        .map(|(index, _)| index)
+        .map(|index| queue.remove(index));
+    drop(queue);
+
+    for request in expired {
+        let reason = eyre!("{:?} expired while queued", request.command);
+        reject(request, reason);
+    }
+    next
+}
+
+/// Waits for the next command for `device`
+///
+/// The command has to be executed while holding the returned guard. Commands
+/// stay queued while the queue is paused, so they can still be superseded.
+pub async fn next(device: Device) -> (BleRequest, MutexGuard<'static, ()>) {
+    loop {
+        let link = device.link().lock().await;
+        if let Some(request) = pop(device) {
+            return (request, link);
+        }
+        drop(link);
+        device.ready().notified().await;
+    }
+}
+
+/// Stops executing commands for `device` until the guard is dropped, waits
+/// for the command that is currently executed
+pub async fn pause(device: Device) -> MutexGuard<'static, ()> {
+    device.link().lock().await
+}
+
+/// Pending command for diagnostics
+#[derive(Debug, Clone)]
+pub struct QueuedCommand {
+    pub command: Command,
+    /// Time until the command is dropped
+    pub remaining: Duration,
+}
+
+pub fn snapshot() -> Vec<QueuedCommand> {
+    let now = Instant::now();
+    QUEUE
+        .lock()
+        .expect("queue lock poisoned")
+        .iter()
+        .map(|request| QueuedCommand {
+            command: request.command.to_owned(),
+            remaining: request.deadline.saturating_duration_since(now),
+        })
+        .collect()
+}
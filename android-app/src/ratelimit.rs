@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::config::{self, RateLimitConfig};
+
+const LOCKOUT_PATH: &str = "/data/data/com.erik_tesar.car.remote/lockout.json";
+
+/// Upper bound of tracked senders, spoofed random numbers must not exhaust
+/// the memory
+const MAX_TRACKED_NUMBERS: usize = 256;
+
+/// Continuously refilled token bucket
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, per_minute: u32) -> Self {
+        Self {
+            tokens: capacity as f64,
+            capacity: capacity as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = Instant::now();
+    }
+
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Failed authentication attempts of a single number
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Lockout {
+    /// consecutive failures since the last lockout or success
+    failures: u32,
+    /// number of lockouts without a successful command in between, used as
+    /// exponent for the lockout duration
+    lockouts: u32,
+    /// Unix timestamp in seconds
+    locked_until: u64,
+}
+
+#[derive(Debug)]
+pub enum Decision {
+    Allowed,
+    RateLimited,
+    LockedOut { until: SystemTime },
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    global: TokenBucket,
+    per_number: HashMap<String, TokenBucket>,
+    lockouts: HashMap<String, Lockout>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl RateLimiter {
+    /// Restores persisted lockouts, so a restart does not lift them
+    pub async fn load() -> Self {
+        let config = config::get().rate_limit.to_owned();
+        let lockouts = match tokio::fs::read(LOCKOUT_PATH).await {
+            Ok(buf) => serde_json::from_slice(&buf).unwrap_or_else(|e| {
+                error!("Invalid lockout state, starting empty: {e}");
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Failed to read lockout state: {e}");
+                HashMap::new()
+            }
+        };
+        Self::new(config, lockouts)
+    }
+
+    fn new(
+        config: RateLimitConfig,
+        lockouts: HashMap<String, Lockout>,
+    ) -> Self {
+        Self {
+            global: TokenBucket::new(
+                config.global_capacity,
+                config.global_per_minute,
+            ),
+            config,
+            per_number: HashMap::new(),
+            lockouts,
+        }
+    }
+
+    /// Checks if a SMS from `number` may be processed and consumes a token of
+    /// the number
+    ///
+    /// The global bucket is not touched, see [`Self::check_global`].
+    pub fn check(&mut self, number: &str) -> Decision {
+        if let Some(lockout) = self.lockouts.get(number) {
+            if lockout.locked_until > unix_now() {
+                return Decision::LockedOut {
+                    until: SystemTime::UNIX_EPOCH
+                        + Duration::from_secs(lockout.locked_until),
+                };
+            }
+        }
+
+        if self.per_number.len() >= MAX_TRACKED_NUMBERS {
+            self.per_number.retain(|_, bucket| !bucket.is_full());
+        }
+        let (capacity, per_minute) =
+            (self.config.number_capacity, self.config.number_per_minute);
+        let allowed = self
+            .per_number
+            .entry(number.to_string())
+            .or_insert_with(|| TokenBucket::new(capacity, per_minute))
+            .try_take();
+        if allowed {
+            Decision::Allowed
+        } else {
+            Decision::RateLimited
+        }
+    }
+
+    /// Consumes a token of the bucket shared by all numbers
+    ///
+    /// Only called for authorized numbers, otherwise spoofed senders could
+    /// starve the commands of the owner.
+    pub fn check_global(&mut self) -> Decision {
+        if self.global.try_take() {
+            Decision::Allowed
+        } else {
+            Decision::RateLimited
+        }
+    }
+
+    /// Records a failed authentication and returns the end of the lockout if
+    /// it triggered one
+    ///
+    /// Only lockouts are persisted, so unauthorized SMS do not cause a write
+    /// each. The failures before a lockout start over after a restart.
+    pub async fn record_failure(&mut self, number: &str) -> Option<SystemTime> {
+        let duration = self.failure(number, unix_now())?;
+        self.persist().await;
+        Some(SystemTime::now() + Duration::from_secs(duration))
+    }
+
+    /// Returns the lockout duration in seconds if the failure triggered one
+    fn failure(&mut self, number: &str, now: u64) -> Option<u64> {
+        if !self.lockouts.contains_key(number)
+            && self.lockouts.len() >= MAX_TRACKED_NUMBERS
+        {
+            self.lockouts
+                .retain(|_, lockout| lockout.locked_until > now);
+            // all tracked numbers are locked, the one expiring first loses
+            // its lockout
+            if self.lockouts.len() >= MAX_TRACKED_NUMBERS {
+                if let Some(number) = self
+                    .lockouts
+                    .iter()
+                    .min_by_key(|(_, lockout)| lockout.locked_until)
+                    .map(|(number, _)| number.to_owned())
+                {
+                    self.lockouts.remove(&number);
+                }
+            }
+        }
+        let lockout = self.lockouts.entry(number.to_string()).or_default();
+        lockout.failures += 1;
+        if lockout.failures < self.config.max_failures {
+            return None;
+        }
+        let duration = self
+            .config
+            .lockout_base_secs
+            .saturating_mul(2u64.saturating_pow(lockout.lockouts))
+            .min(self.config.lockout_max_secs);
+        lockout.failures = 0;
+        lockout.lockouts += 1;
+        lockout.locked_until = now + duration;
+        Some(duration)
+    }
+
+    pub async fn record_success(&mut self, number: &str) {
+        if self.lockouts.remove(number).is_some() {
+            self.persist().await;
+        }
+    }
+
+    async fn persist(&self) {
+        let ser = match serde_json::to_vec(&self.lockouts) {
+            Ok(ser) => ser,
+            Err(e) => {
+                error!("Failed to serialize lockout state: {e}");
+                return;
+            }
+        };
+        if let Err(e) = tokio::fs::write(LOCKOUT_PATH, ser).await {
+            error!("Failed to persist lockout state: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        unix_now, Decision, Lockout, RateLimiter, TokenBucket,
+        MAX_TRACKED_NUMBERS,
+    };
+    use crate::config::RateLimitConfig;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig::default(), HashMap::new())
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(2, 60);
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        // one token per second
+        bucket.last_refill = Instant::now() - Duration::from_secs(1);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        // never more than the capacity
+        bucket.last_refill = Instant::now() - Duration::from_secs(60);
+        assert!(bucket.is_full());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn per_number_before_global() {
+        let mut limiter = limiter();
+        let capacity = limiter.config.number_capacity;
+        for _ in 0..capacity {
+            assert!(matches!(limiter.check("+1"), Decision::Allowed));
+        }
+        assert!(matches!(limiter.check("+1"), Decision::RateLimited));
+        // a limited sender does not use up the global bucket
+        assert!(matches!(limiter.check("+2"), Decision::Allowed));
+        for _ in 0..limiter.config.global_capacity {
+            assert!(matches!(limiter.check_global(), Decision::Allowed));
+        }
+        assert!(matches!(limiter.check_global(), Decision::RateLimited));
+    }
+
+    #[test]
+    fn lockout_backoff() {
+        let mut limiter = limiter();
+        let config = limiter.config.to_owned();
+        let now = unix_now();
+        for lockouts in 0..3 {
+            for _ in 1..config.max_failures {
+                assert_eq!(limiter.failure("+1", now), None);
+            }
+            assert_eq!(
+                limiter.failure("+1", now),
+                Some(config.lockout_base_secs << lockouts)
+            );
+        }
+        assert!(matches!(limiter.check("+1"), Decision::LockedOut { .. }));
+        assert!(matches!(limiter.check("+2"), Decision::Allowed));
+
+        for _ in 0..64 * config.max_failures {
+            limiter.failure("+1", now);
+        }
+        assert_eq!(
+            limiter.lockouts["+1"].locked_until,
+            now + config.lockout_max_secs
+        );
+    }
+
+    #[test]
+    fn lockouts_bounded() {
+        let now = unix_now();
+        let lockouts = (0..MAX_TRACKED_NUMBERS)
+            .map(|i| {
+                let lockout = Lockout {
+                    failures: 0,
+                    lockouts: 1,
+                    locked_until: now + 3600 - i as u64,
+                };
+                (format!("+{i}"), lockout)
+            })
+            .collect();
+        let mut limiter =
+            RateLimiter::new(RateLimitConfig::default(), lockouts);
+        for _ in 0..limiter.config.max_failures {
+            limiter.failure("+new", now);
+        }
+        assert_eq!(limiter.lockouts.len(), MAX_TRACKED_NUMBERS);
+        // the lockout expiring first was evicted
+        let last = format!("+{}", MAX_TRACKED_NUMBERS - 1);
+        assert!(!limiter.lockouts.contains_key(&last));
+        assert!(limiter.lockouts.contains_key("+0"));
+        assert!(limiter.lockouts["+new"].locked_until > now);
+    }
+}
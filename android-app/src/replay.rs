@@ -0,0 +1,149 @@
+//! Replay protection for signed commands
+//!
+//! Signatures of executed commands are remembered until the command expires
+//! and persisted, so a restart of the hub does not make them valid again.
+//! Commands that expire too far in the future are rejected, which bounds how
+//! long a signature has to be remembered.
+
+use std::{collections::BTreeMap, io::ErrorKind, time::SystemTime};
+
+const SEEN_COMMANDS_PATH: &str =
+    "/data/data/com.erik_tesar.car.remote/seen_commands.json";
+
+/// Longest accepted time between now and the expiration of a command
+pub const MAX_COMMAND_LIFETIME_SECS: u64 = 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Expired,
+    /// Expires later than [`MAX_COMMAND_LIFETIME_SECS`] from now
+    ExpiresTooLate,
+    Replayed,
+}
+
+/// Signatures of already executed commands with their expiration
+#[derive(Debug, Default)]
+pub struct SeenCommands {
+    seen: BTreeMap<Vec<u8>, u64>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SeenCommands {
+    /// Restores the persisted signatures
+    pub async fn load() -> Self {
+        // JSON maps need string keys
+        let seen: Vec<(Vec<u8>, u64)> =
+            match tokio::fs::read(SEEN_COMMANDS_PATH).await {
+                Ok(buf) => serde_json::from_slice(&buf).unwrap_or_else(|e| {
+                    error!("Invalid seen commands, starting empty: {e}");
+                    Vec::new()
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(e) => {
+                    error!("Failed to read seen commands: {e}");
+                    Vec::new()
+                }
+            };
+        Self {
+            seen: seen.into_iter().collect(),
+        }
+    }
+
+    /// Remembers the command if it may be executed
+    pub async fn check(
+        &mut self,
+        id: Vec<u8>,
+        expiration: u64,
+    ) -> Result<(), Rejection> {
+        self.check_at(id, expiration, unix_now())?;
+        self.persist().await;
+        Ok(())
+    }
+
+    fn check_at(
+        &mut self,
+        id: Vec<u8>,
+        expiration: u64,
+        now: u64,
+    ) -> Result<(), Rejection> {
+        // expired commands are rejected anyway, no need to remember them
+        self.seen.retain(|_, exp| *exp >= now);
+        if expiration < now {
+            return Err(Rejection::Expired);
+        }
+        if expiration - now > MAX_COMMAND_LIFETIME_SECS {
+            return Err(Rejection::ExpiresTooLate);
+        }
+        if self.seen.contains_key(&id) {
+            return Err(Rejection::Replayed);
+        }
+        self.seen.insert(id, expiration);
+        Ok(())
+    }
+
+    async fn persist(&self) {
+        let ser =
+            match serde_json::to_vec(&self.seen.iter().collect::<Vec<_>>()) {
+                Ok(ser) => ser,
+                Err(e) => {
+                    error!("Failed to serialize seen commands: {e}");
+                    return;
+                }
+            };
+        if let Err(e) = tokio::fs::write(SEEN_COMMANDS_PATH, ser).await {
+            error!("Failed to persist seen commands: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rejection, SeenCommands, MAX_COMMAND_LIFETIME_SECS};
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn accepts_once() {
+        let mut seen = SeenCommands::default();
+        assert_eq!(seen.check_at(vec![1], NOW + 60, NOW), Ok(()));
+        assert_eq!(
+            seen.check_at(vec![1], NOW + 60, NOW + 1),
+            Err(Rejection::Replayed)
+        );
+        assert_eq!(seen.check_at(vec![2], NOW + 60, NOW + 1), Ok(()));
+    }
+
+    #[test]
+    fn expiration() {
+        let mut seen = SeenCommands::default();
+        assert_eq!(
+            seen.check_at(vec![1], NOW - 1, NOW),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(seen.check_at(vec![1], 0, NOW), Err(Rejection::Expired));
+        assert_eq!(
+            seen.check_at(vec![1], NOW + MAX_COMMAND_LIFETIME_SECS + 1, NOW),
+            Err(Rejection::ExpiresTooLate)
+        );
+        assert_eq!(
+            seen.check_at(vec![1], NOW + MAX_COMMAND_LIFETIME_SECS, NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn forgets_expired() {
+        let mut seen = SeenCommands::default();
+        seen.check_at(vec![1], NOW + 60, NOW).unwrap();
+        seen.check_at(vec![2], NOW + 120, NOW).unwrap();
+        seen.check_at(vec![3], NOW + 180, NOW + 61).unwrap();
+        assert_eq!(seen.seen.len(), 2);
+        assert!(!seen.seen.contains_key([1].as_slice()));
+    }
+}
@@ -3,10 +3,31 @@ use uuid::Uuid;
 pub const ENGINE_SERVICE_UUID: Uuid =
     Uuid::from_u128(0x0e353531515942a092ff38e9e49ab7d1);
 
-/// Read, Write, Notify
+/// Read, Notify
 pub const ENGINE_STATE_CHAR: Uuid =
     Uuid::from_u128(0x13d24b593d134ef798dbe174869078e0);
 
+/// Read, Write, engine state to switch to with counter and MAC, reads the
+/// last accepted counter
+pub const ENGINE_COMMAND_CHAR: Uuid =
+    Uuid::from_u128(0x217a5b7dc7fe4cc0bc8f58be18c286e1);
+
+/// Read, Notify, see [`KeyPosition`]
+pub const KEY_POSITION_CHAR: Uuid =
+    Uuid::from_u128(0x58939e8992e243cb8257625dfb1128d3);
+
+/// Read, Notify, see [`Arbitration`]
+pub const ARBITRATION_CHAR: Uuid =
+    Uuid::from_u128(0x965b36e2e22f4685841c804c3acff58c);
+
+/// Read, postcard encoded [`StarterDiagnostics`]
+pub const DIAGNOSTICS_CHAR: Uuid =
+    Uuid::from_u128(0xf44979f7b1894156a282153087f71c94);
+
+/// Read, Write, text of the last panic, cleared by writing anything
+pub const STARTER_PANIC_CHAR: Uuid =
+    Uuid::from_u128(0x8ad7bedf141a4d20baed15778e5aebc1);
+
 pub const DOOR_SERVICE_UUID: Uuid =
     Uuid::from_u128(0x5eb5b1175231409ea1cab7689f488473);
 
@@ -19,15 +40,76 @@ pub const DOOR_WINDOW_LEFT_CHAR: Uuid =
 /// 0 window up, 1 window down
 pub const DOOR_WINDOW_RIGHT_CHAR: Uuid =
     Uuid::from_u128(0x8f738eeebbb74cce8b82726a56532bdc);
+/// Read, Write, text of the last panic, cleared by writing anything
+pub const DOOR_PANIC_CHAR: Uuid =
+    Uuid::from_u128(0x486d3e6952ac4bf8b338c1e15d171026);
+
+/// Read, Write, challenge of a new bond on both devices, empty once the bond
+/// is authorized, takes the displayed passkey sealed with the pairing key
+pub const AUTH_CHAR: Uuid = Uuid::from_u128(0x92f50f10b2a84da1bcb743493b4fa736);
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, Clone)]
+/// Firmware update service of both devices
+pub const OTA_SERVICE_UUID: Uuid =
+    Uuid::from_u128(0xc6422fa864b3485db9c39d86fee29183);
+/// Write, begin, finish or abort an update
+pub const OTA_CONTROL_CHAR: Uuid =
+    Uuid::from_u128(0xe32a319fcfa44838aac359fde6058ee1);
+/// Write, offset and chunk of the image
+pub const OTA_DATA_CHAR: Uuid =
+    Uuid::from_u128(0x52c97e61f1004892ae26d98e8b2bacbf);
+/// Read, postcard encoded [`OtaStatus`]
+pub const OTA_STATUS_CHAR: Uuid =
+    Uuid::from_u128(0xffb703e1455c486ba902d4a15bbad0fb);
+
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+)]
 #[serde(untagged)]
 pub enum Command {
     DoorController(DoorControllerCommand),
     Engine(EngineCommand),
+    Query(QueryCommand),
+}
+
+impl Command {
+    /// Single byte representation used by the compact SMS encoding
+    pub fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0x00 => Self::Engine(EngineCommand::Off),
+            0x01 => Self::Engine(EngineCommand::Radio),
+            0x02 => Self::Engine(EngineCommand::Engine),
+            0x03 => Self::Engine(EngineCommand::Ignition),
+            0x10 => Self::DoorController(DoorControllerCommand::Lock),
+            0x11 => Self::DoorController(DoorControllerCommand::Unlock),
+            0x12 => Self::DoorController(DoorControllerCommand::WindowLeftUp),
+            0x13 => Self::DoorController(DoorControllerCommand::WindowLeftDown),
+            0x14 => Self::DoorController(DoorControllerCommand::WindowRightUp),
+            0x15 => {
+                Self::DoorController(DoorControllerCommand::WindowRightDown)
+            }
+            0x20 => Self::Query(QueryCommand::Status),
+            _ => return None,
+        })
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, Clone)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Deserialize,
+    serde::Serialize,
+    Clone,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum DoorControllerCommand {
     Lock,
@@ -38,7 +120,16 @@ pub enum DoorControllerCommand {
     WindowRightDown,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, serde::Deserialize)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    serde::Deserialize,
+    serde::Serialize,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum EngineCommand {
     Off,
@@ -46,3 +137,105 @@ pub enum EngineCommand {
     Engine,
     Ignition,
 }
+
+/// Commands that do not write a characteristic but reply with data via SMS
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryCommand {
+    Status,
+}
+
+/// Position of the physical key as reported by the starter
+#[derive(Debug, PartialEq, Eq, Clone, Copy, serde::Deserialize)]
+pub enum KeyPosition {
+    Off,
+    Radio,
+    Engine,
+    Ignition,
+}
+
+impl KeyPosition {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => Self::Off,
+            1 => Self::Radio,
+            2 => Self::Engine,
+            3 => Self::Ignition,
+            _ => return None,
+        })
+    }
+}
+
+/// Who controls the relays of the starter
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Arbitration {
+    Key,
+    Ble,
+    /// The key was turned to radio while the engine was held via BLE
+    BleHeldWhileKeyRadio,
+}
+
+impl Arbitration {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => Self::Key,
+            1 => Self::Ble,
+            2 => Self::BleHeldWhileKeyRadio,
+            _ => return None,
+        })
+    }
+}
+
+/// Persisted counters of the starter
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct StarterCounters {
+    pub boots: u32,
+    pub panics: u32,
+    /// Actuations of the relays
+    pub radio: u32,
+    pub engine: u32,
+    pub engine_consumers: u32,
+    pub ignition: u32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct KeyAnomaly {
+    pub from: KeyPosition,
+    pub to: KeyPosition,
+    pub uptime_secs: u64,
+}
+
+/// Must match the field order of the starter firmware as postcard is not self
+/// describing
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct StarterDiagnostics {
+    pub firmware_version: String,
+    pub uptime_secs: u64,
+    /// Raw `SocResetReason` of the ESP32-C3
+    pub reset_reason: Option<u8>,
+    pub counters: StarterCounters,
+    pub key_anomalies: u32,
+    pub last_key_anomaly: Option<KeyAnomaly>,
+}
+
+/// Update state of a device, must match the field order of the firmwares
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct OtaStatus {
+    pub running_slot: u8,
+    /// Start of the SHA-256 of the running image
+    pub running_hash: [u8; 20],
+    pub confirmed: bool,
+    /// Size of the image being uploaded, zero if there is none
+    pub size: u32,
+    /// Bytes of the image the device has written
+    pub offset: u32,
+}
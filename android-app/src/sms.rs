@@ -5,10 +5,12 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use btleplug::api::BDAddr;
 use color_eyre::eyre::eyre;
+use data_encoding::BASE64URL_NOPAD;
 use jni::{
-    objects::{AutoLocal, JClass, JObject, JString},
-    JNIEnv,
+    objects::{AutoLocal, GlobalRef, JClass, JObject, JString},
+    JNIEnv, JavaVM,
 };
 use jose::{
     crypto::hmac::Hs256,
@@ -22,39 +24,86 @@ use jose::{
 use jose::{crypto::hmac::Key as HmacKey, jwa::Hmac};
 
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
     sync::{
         mpsc::{UnboundedReceiver, UnboundedSender},
-        Mutex,
+        oneshot, Mutex,
     },
     task::JoinHandle,
     time::sleep,
 };
 
 use crate::{
-    ble::{try_reconnect_door_controller, ENGINE_STATUS},
-    log_error,
-    schema::{Command, DoorControllerCommand, EngineCommand},
+    audit::{self, AuditEntry, BleOutcome, PolicyDecision, Verification},
+    ble::{self, try_reconnect_door_controller, BleAck, BleRequest},
+    compact, config,
+    engine::EngineHold,
+    log_error, phone,
+    ratelimit::{Decision, RateLimiter},
+    replay::{Rejection, SeenCommands},
+    schema::{Command, DoorControllerCommand, QueryCommand},
+    status::Status,
 };
 
 static SMS_SENDER: OnceLock<UnboundedSender<Sms>> = OnceLock::new();
-static SMS_VERIFIER: Mutex<Option<JwkVerifier>> = Mutex::const_new(None);
+static SMS_VERIFIER: Mutex<Option<SmsKey>> = Mutex::const_new(None);
 static SMS_AUTHORIZED_PHONE_NUMBERS: OnceLock<Vec<String>> = OnceLock::new();
 const SMS_VERIFIER_KEY_PATH: &str =
     "/data/data/com.erik_tesar.car.remote/sms_verifer_key.json";
 
+static JAVA_VM: OnceLock<JavaVM> = OnceLock::new();
+// classes must be looked up from a thread created by java, because native
+// threads only see the system class loader, therefore cache it on init
+static RUST_SERVICE_CLASS: OnceLock<GlobalRef> = OnceLock::new();
+const RUST_SERVICE_CLASS_NAME: &str = "com/erik_tesar/car/remote/RustService";
+
 #[derive(Debug, serde::Deserialize)]
 struct CommandRepr {
     cmd: Command,
 }
 
+struct SmsKey {
+    verifier: JwkVerifier,
+    /// raw HMAC secret used for the compact encoding
+    secret: Vec<u8>,
+    /// fingerprint of the secret for the audit log
+    id: String,
+}
+
+/// Command whose signature has been checked, independent of the encoding
+pub struct VerifiedCommand {
+    pub command: Command,
+    /// Unix timestamp in seconds after which the command must not be executed
+    pub expiration: u64,
+    /// Signature of the command, used to detect replays
+    pub id: Vec<u8>,
+}
+
+/// First 8 bytes of the SHA-256 of the secret as hex
+fn key_id(secret: &[u8]) -> String {
+    Sha256::digest(secret)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn secret_from_jwk(jwk: &serde_json::Value) -> color_eyre::Result<Vec<u8>> {
+    let k = jwk["k"].as_str().ok_or(eyre!("SMS key has no secret"))?;
+    Ok(BASE64URL_NOPAD.decode(k.as_bytes())?)
+}
+
 pub async fn init(
-    ble_sender: UnboundedSender<Command>,
+    env: &JNIEnv<'_>,
+    ble_sender: UnboundedSender<BleRequest>,
 ) -> color_eyre::Result<JoinHandle<Result<Infallible, color_eyre::Report>>> {
     let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<Sms>();
     SMS_SENDER.set(sender).ok();
+    JAVA_VM.set(env.get_java_vm()?).ok();
+    let class = env.find_class(RUST_SERVICE_CLASS_NAME)?;
+    RUST_SERVICE_CLASS.set(env.new_global_ref(class)?).ok();
     {
         match File::open(SMS_VERIFIER_KEY_PATH)
             .await
@@ -63,6 +112,7 @@ pub async fn init(
             Ok(mut file) => {
                 let mut buf = vec![];
                 file.read_to_end(&mut buf).await?;
+                let secret = secret_from_jwk(&serde_json::from_slice(&buf)?)?;
                 let key: JsonWebKey = serde_json::from_slice(&buf)?;
                 let key =
                     key.check(StandardPolicy::default()).map_err(|(_, e)| e)?;
@@ -70,7 +120,12 @@ pub async fn init(
                     JsonWebSigningAlgorithm::Hmac(Hmac::Hs256),
                 )?;
                 let mut sms_verifier = SMS_VERIFIER.lock().await;
-                *sms_verifier = Some(verifier);
+                let id = key_id(&secret);
+                *sms_verifier = Some(SmsKey {
+                    verifier,
+                    secret,
+                    id,
+                });
                 info!("Loaded SMS verifier key from storage");
             }
             Err(ErrorKind::NotFound) => {
@@ -90,25 +145,19 @@ pub async fn init(
 }
 
 pub async fn enable_engine_for_door_controller(
-    ble_sender: UnboundedSender<Command>,
+    ble_sender: UnboundedSender<BleRequest>,
     door_command: DoorControllerCommand,
-) {
-    tokio::spawn(async {
-        log_error(
+) -> BleAck {
+    let (reply, ack) = oneshot::channel();
+    tokio::spawn(async move {
+        let res = log_error(
             "hold engine",
             async move {
-                let restore_state = ENGINE_STATUS.read().await.to_owned();
-                let already_in_engine = matches!(restore_state, EngineCommand::Engine | EngineCommand::Ignition);
+                let hold = EngineHold::acquire(&ble_sender).await?;
 
                 info!("Enable engine for door controller");
-                if !already_in_engine {
-                        ble_sender
-                            .send(Command::Engine(EngineCommand::Engine))?;
-                }
-
-
                 // if already in engine then we can already use it
-                if try_reconnect_door_controller().await {
+                let res = if try_reconnect_door_controller().await {
                     let hold_engine = Duration::from_secs(match door_command {
                         DoorControllerCommand::Lock
                         | DoorControllerCommand::Unlock => 3,
@@ -117,149 +166,337 @@ pub async fn enable_engine_for_door_controller(
                         | DoorControllerCommand::WindowRightDown
                         | DoorControllerCommand::WindowRightUp => 10,
                     });
-                    ble_sender.send(Command::DoorController(door_command))?;
+                    let res = ble::send(&ble_sender, Command::DoorController(door_command)).await;
                     // hold the engine in state `engine` because the door controller got no power otherwise
-                    sleep(hold_engine).await;
-                    if !already_in_engine {
-                        info!("Restoring Engine state to {restore_state:?}");
-                        ble_sender.send(Command::Engine(restore_state))?;
+                    if res.is_ok() {
+                        sleep(hold_engine).await;
                     }
-                    Ok(())
+                    res
                 } else {
                     Err(eyre!("Door controller not connected, cannot perform {door_command:?}"))
-                }
+                };
+                hold.release().await?;
+                res
             }
             .await,
-        )
+        );
+        let _ = reply.send(res);
     });
+    ack
+}
+
+async fn reply_to_query(number: String, query: QueryCommand) {
+    tokio::spawn(async move {
+        let reply = match query {
+            QueryCommand::Status => Status::collect().await.to_string(),
+        };
+        info!("Replying to {query:?}: {reply}");
+        let _ = log_error("reply to query", send_sms(&number, &reply));
+    });
+}
+
+/// Notifies all authorized numbers, e.g. about a lockout
+pub fn alert(text: &str) {
+    warn!("Alert: {text}");
+    for number in SMS_AUTHORIZED_PHONE_NUMBERS.get().into_iter().flatten() {
+        let _ = log_error("send alert", send_sms(number, text));
+    }
+}
+
+fn alert_lockout(number: &str, until: SystemTime) {
+    let minutes = until
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .as_secs()
+        .div_ceil(60);
+    alert(&format!(
+        "Car: {number} locked out for {minutes} min after repeated failed commands"
+    ));
+}
+
+/// Sends a SMS via the android `SmsManager`
+pub fn send_sms(number: &str, text: &str) -> color_eyre::Result<()> {
+    let vm = JAVA_VM.get().ok_or(eyre!("Java VM not initialized"))?;
+    let class = RUST_SERVICE_CLASS
+        .get()
+        .ok_or(eyre!("RustService class not initialized"))?;
+    let env = vm.attach_current_thread()?;
+    let number = env.new_string(number)?;
+    let text = env.new_string(text)?;
+    env.call_static_method(
+        class,
+        "sendSms",
+        "(Ljava/lang/String;Ljava/lang/String;)V",
+        &[number.into(), text.into()],
+    )?;
+    Ok(())
+}
+
+/// Reads the RSSI of a connected device via the android `BluetoothGatt`
+///
+/// Blocks until android answered or gave up. Returns `None` if the device is
+/// not connected anymore.
+pub fn read_rssi(address: BDAddr) -> color_eyre::Result<Option<i16>> {
+    let vm = JAVA_VM.get().ok_or(eyre!("Java VM not initialized"))?;
+    let class = RUST_SERVICE_CLASS
+        .get()
+        .ok_or(eyre!("RustService class not initialized"))?;
+    let env = vm.attach_current_thread()?;
+    let address = env.new_string(address.to_string())?;
+    let rssi = env
+        .call_static_method(
+            class,
+            "readRssi",
+            "(Ljava/lang/String;)I",
+            &[address.into()],
+        )?
+        .i()?;
+    // android reports the RSSI in the range of a signed byte
+    Ok(i8::try_from(rssi).ok().map(i16::from))
+}
+
+fn verify_jwt(
+    message: &str,
+    verifier: &mut JwkVerifier,
+) -> color_eyre::Result<VerifiedCommand> {
+    let encoded: Compact = message
+        .parse()
+        .map_err(|e| eyre!("Invalid signed SMS: {e}"))?;
+    let unverified: Unverified<Jwt<CommandRepr>> = Jwt::decode(encoded)
+        .map_err(|e| eyre!("Failed to parse signed SMS: {e:#?}"))?;
+    let jws = unverified
+        .verify(verifier)
+        .map_err(|e| eyre!("SMS signature validation failed: {e}"))?;
+    let signature = message.rsplit('.').next().unwrap_or_default();
+    Ok(VerifiedCommand {
+        command: jws.payload().additional.cmd.to_owned(),
+        expiration: jws.payload().expiration.unwrap_or(0),
+        id: signature.as_bytes().to_vec(),
+    })
 }
 
 pub async fn listen(
-    ble_sender: UnboundedSender<Command>,
+    ble_sender: UnboundedSender<BleRequest>,
     mut sms_receiver: UnboundedReceiver<Sms>,
 ) -> color_eyre::Result<Infallible> {
+    let mut limiter = RateLimiter::load().await;
+    let mut seen = SeenCommands::load().await;
     while let Some(sms) = sms_receiver.recv().await {
-        let authorized =
-            if let Some(authorized) = SMS_AUTHORIZED_PHONE_NUMBERS.get() {
-                authorized.contains(&sms.number.trim().to_string())
-            } else {
-                warn!("No phone number found for Admin contact");
-                false
-            };
-        if !authorized {
-            warn!(
-                "Ignoring SMS from unauthorized number: {}\nSMS: {}",
-                sms.number, sms.message
-            );
-            continue;
-        }
-        let mut sms_verifier = SMS_VERIFIER.lock().await;
-        match &mut *sms_verifier {
-            Some(verifier) => {
-                let encoded: Compact = match sms.message.trim().parse() {
-                    Ok(encoded) => encoded,
-                    Err(e) => {
-                        warn!("Invalid signed SMS: {e}");
-                        continue;
-                    }
-                };
-                let unverified: Unverified<Jwt<CommandRepr>> =
-                    match Jwt::decode(encoded) {
-                        Ok(o) => o,
-                        Err(e) => {
-                            warn!("Failed to parse signed SMS: {e:#?}");
-                            continue;
-                        }
+        let mut entry = AuditEntry::new(&sms.number);
+        match handle_sms(&ble_sender, &mut limiter, &mut seen, &sms, &mut entry)
+            .await
+        {
+            Ok(Some(ack)) => {
+                // do not block other SMS while the command is executed
+                tokio::spawn(async move {
+                    entry.ble = match ack.await {
+                        Ok(Ok(())) => BleOutcome::Ok,
+                        Ok(Err(e)) => BleOutcome::Failed(e.to_string()),
+                        Err(_) => BleOutcome::Failed(
+                            "BLE listener dropped the command".to_string(),
+                        ),
                     };
-                match unverified.verify(verifier) {
-                    Ok(jws) => {
-                        drop(sms_verifier);
-                        let exp = jws.payload().expiration.unwrap_or(0);
-                        let exp =
-                            SystemTime::UNIX_EPOCH + Duration::from_secs(exp);
-                        if SystemTime::now() > exp {
-                            error!("Signed SMS is expired");
-                            continue;
-                        }
+                    audit::append(entry).await;
+                });
+            }
+            Ok(None) => audit::append(entry).await,
+            Err(e) => {
+                audit::append(entry).await;
+                return Err(e);
+            }
+        }
+    }
+    Err(eyre!("Channel hung up"))
+}
 
-                        info!(
-                            "Verified command: {:?}",
-                            jws.payload().additional.cmd
-                        );
-                        match jws.payload().additional.cmd.to_owned() {
-                            Command::Engine(engine) => {
-                                ble_sender.send(Command::Engine(engine))?
-                            }
-                            Command::DoorController(door) => {
-                                enable_engine_for_door_controller(
-                                    ble_sender.clone(),
-                                    door,
-                                )
-                                .await;
-                            }
-                        }
+/// Returns the acknowledgement if the SMS resulted in a BLE command
+async fn handle_sms(
+    ble_sender: &UnboundedSender<BleRequest>,
+    limiter: &mut RateLimiter,
+    seen: &mut SeenCommands,
+    sms: &Sms,
+    entry: &mut AuditEntry,
+) -> color_eyre::Result<Option<BleAck>> {
+    let country_code = &config::get().default_country_code;
+    let sender = phone::normalize(&sms.number, country_code);
+    match limiter.check(&sender) {
+        Decision::Allowed => {}
+        Decision::RateLimited => {
+            warn!("Rate limited SMS from {sender}");
+            entry.decision = PolicyDecision::RateLimited;
+            return Ok(None);
+        }
+        Decision::LockedOut { until } => {
+            warn!("Ignoring SMS from {sender}, locked out until {until:?}");
+            entry.decision = PolicyDecision::LockedOut;
+            return Ok(None);
+        }
+    }
+    let authorized =
+        if let Some(authorized) = SMS_AUTHORIZED_PHONE_NUMBERS.get() {
+            authorized
+                .iter()
+                .any(|n| phone::normalize(n, country_code) == sender)
+        } else {
+            warn!("No phone number found for Admin contact");
+            false
+        };
+    if !authorized {
+        warn!(
+            "Ignoring SMS from unauthorized number: {}\nSMS: {}",
+            sms.number, sms.message
+        );
+        entry.decision = PolicyDecision::Unauthorized;
+        if let Some(until) = limiter.record_failure(&sender).await {
+            // no SMS alert, otherwise spoofed numbers could make the hub
+            // send arbitrary many messages
+            warn!("Locked out unauthorized number {sender} until {until:?}");
+        }
+        return Ok(None);
+    }
+    if let Decision::RateLimited = limiter.check_global() {
+        warn!("Rate limited SMS from {sender}, too many SMS from all numbers");
+        entry.decision = PolicyDecision::RateLimited;
+        return Ok(None);
+    }
+    let mut sms_verifier = SMS_VERIFIER.lock().await;
+    match &mut *sms_verifier {
+        Some(key) => {
+            let message = sms.message.trim();
+            entry.key_id = Some(key.id.to_owned());
+            let verified = if compact::is_compact(message) {
+                entry.format = Some("compact".to_string());
+                compact::verify(message, &key.secret)
+            } else {
+                entry.format = Some("jwt".to_string());
+                verify_jwt(message, &mut key.verifier)
+            };
+            drop(sms_verifier);
+            let verified = match verified {
+                Ok(verified) => verified,
+                Err(e) => {
+                    error!("SMS verification failed: {e}");
+                    entry.verification = Verification::Invalid(e.to_string());
+                    entry.decision = PolicyDecision::InvalidSignature;
+                    if let Some(until) = limiter.record_failure(&sender).await {
+                        alert_lockout(&sender, until);
                     }
-                    Err(e) => {
-                        error!("SMS signature validation failed: {e}")
+                    return Ok(None);
+                }
+            };
+            entry.verification = Verification::Valid;
+            entry.command = Some(verified.command.to_owned());
+            match seen.check(verified.id, verified.expiration).await {
+                Ok(()) => {}
+                Err(Rejection::Expired) => {
+                    error!("Signed SMS is expired");
+                    entry.decision = PolicyDecision::Expired;
+                    return Ok(None);
+                }
+                Err(Rejection::ExpiresTooLate) => {
+                    error!("Signed SMS expires too far in the future");
+                    entry.decision = PolicyDecision::ExpiresTooLate;
+                    return Ok(None);
+                }
+                Err(Rejection::Replayed) => {
+                    error!("Signed SMS was already used, ignoring replay");
+                    entry.decision = PolicyDecision::Replayed;
+                    if let Some(until) = limiter.record_failure(&sender).await {
+                        alert_lockout(&sender, until);
                     }
+                    return Ok(None);
                 }
             }
-            None => {
-                if sms.message.trim().starts_with("setup:") {
-                    if let Some((_, key)) = sms.message.split_once("setup:") {
-                        let key = key.trim();
-                        let key = json!({
-                            "kty": "oct",
-                            "k": key,
+            limiter.record_success(&sender).await;
+            entry.decision = PolicyDecision::Accepted;
 
-                        });
-                        let key: OctetSequence =
-                            match serde_json::from_value(key) {
-                                Ok(key) => key,
-                                Err(e) => {
-                                    error!("Invalid setup key: {e}");
-                                    continue;
-                                }
-                            };
-                        let verifier: HmacKey<Hs256> = match key.into_verifier(
-                            JsonWebSigningAlgorithm::Hmac(Hmac::Hs256),
-                        ) {
-                            Ok(verifier) => verifier,
-                            Err(e) => {
-                                error!(
-                                    "Cannot convert setup key to HmacKey: {e}"
-                                );
-                                continue;
-                            }
-                        };
-                        let jwk = verifier.into_jwk(Some(()))?;
-                        let ser = serde_json::to_vec(&jwk)?;
-                        let jwk = jwk
-                            .check(StandardPolicy::default())
-                            .map_err(|(_, e)| e)?;
-                        *sms_verifier = Some(jwk.into_verifier(
+            info!("Verified command: {:?}", verified.command);
+            match verified.command {
+                Command::Engine(engine) => {
+                    let (request, ack) =
+                        BleRequest::new(Command::Engine(engine));
+                    ble_sender
+                        .send(request)
+                        .map_err(|_| eyre!("BLE listener hung up"))?;
+                    entry.ble = BleOutcome::Dispatched;
+                    return Ok(Some(ack));
+                }
+                Command::DoorController(door) => {
+                    let ack = enable_engine_for_door_controller(
+                        ble_sender.clone(),
+                        door,
+                    )
+                    .await;
+                    entry.ble = BleOutcome::Dispatched;
+                    return Ok(Some(ack));
+                }
+                Command::Query(query) => {
+                    reply_to_query(sms.number.to_owned(), query).await;
+                }
+            }
+        }
+        None => {
+            if sms.message.trim().starts_with("setup:") {
+                if let Some((_, key)) = sms.message.split_once("setup:") {
+                    let key = key.trim();
+                    let key = json!({
+                        "kty": "oct",
+                        "k": key,
+
+                    });
+                    let key: OctetSequence = match serde_json::from_value(key) {
+                        Ok(key) => key,
+                        Err(e) => {
+                            error!("Invalid setup key: {e}");
+                            entry.decision = PolicyDecision::SetupRequired;
+                            return Ok(None);
+                        }
+                    };
+                    let verifier: HmacKey<Hs256> = match key.into_verifier(
+                        JsonWebSigningAlgorithm::Hmac(Hmac::Hs256),
+                    ) {
+                        Ok(verifier) => verifier,
+                        Err(e) => {
+                            error!("Cannot convert setup key to HmacKey: {e}");
+                            entry.decision = PolicyDecision::SetupRequired;
+                            return Ok(None);
+                        }
+                    };
+                    let jwk = verifier.into_jwk(Some(()))?;
+                    let ser = serde_json::to_vec(&jwk)?;
+                    let secret = secret_from_jwk(&serde_json::to_value(&jwk)?)?;
+                    let jwk = jwk
+                        .check(StandardPolicy::default())
+                        .map_err(|(_, e)| e)?;
+                    let id = key_id(&secret);
+                    entry.key_id = Some(id.to_owned());
+                    *sms_verifier = Some(SmsKey {
+                        verifier: jwk.into_verifier(
                             JsonWebSigningAlgorithm::Hmac(Hmac::Hs256),
-                        )?);
+                        )?,
+                        secret,
+                        id,
+                    });
 
-                        info!("Setup SMS verification key");
-                        let mut file =
-                            File::create(SMS_VERIFIER_KEY_PATH).await?;
-                        file.write_all(&ser).await?;
-                        info!("Stored SMS verification key");
-                    } else {
-                        error!("setup is missing key");
-                        continue;
-                    }
+                    info!("Setup SMS verification key");
+                    let mut file = File::create(SMS_VERIFIER_KEY_PATH).await?;
+                    file.write_all(&ser).await?;
+                    info!("Stored SMS verification key");
+                    entry.decision = PolicyDecision::Setup;
                 } else {
-                    error!(
-                        "Setup needed to process SMS, ignored sms: `{}`",
-                        sms.message
-                    )
+                    error!("setup is missing key");
+                    entry.decision = PolicyDecision::SetupRequired;
                 }
+            } else {
+                error!(
+                    "Setup needed to process SMS, ignored sms: `{}`",
+                    sms.message
+                );
+                entry.decision = PolicyDecision::SetupRequired;
             }
         }
     }
-    Err(eyre!("Channel hung up"))
+    Ok(None)
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -328,7 +565,9 @@ pub extern "system" fn Java_com_erik_1tesar_car_remote_RustService_provideAuthor
         let obj: AutoLocal = env.auto_local(obj);
         if let Ok(string) = env.get_string(obj.as_obj().into()) {
             if let Ok(string) = string.to_str() {
-                collected.push(string.to_string().split_whitespace().collect());
+                // normalized when comparing because the config might not be
+                // loaded yet
+                collected.push(string.to_string());
             }
         }
     }
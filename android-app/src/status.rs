@@ -0,0 +1,329 @@
+use std::{
+    fmt::Display,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use btleplug::api::Peripheral as _;
+use tokio::sync::RwLock;
+
+use crate::{
+    audit::{self, AuditEntry, PolicyDecision},
+    ble::{self, KeyState, DOOR_CONTROLLER, STARTER},
+    engine::{self, EngineStatus},
+    health::{self, DeviceHealth},
+    ota::{self, Progress, UpdateOutcome},
+    queue::{self, Device, QueuedCommand},
+    schema::{Command, StarterDiagnostics},
+};
+
+/// Battery capacity in percent as exposed by the android kernel
+const BATTERY_CAPACITY_PATH: &str = "/sys/class/power_supply/battery/capacity";
+
+/// Number of audit entries searched for the last rejected SMS
+const REJECTED_LOOKBACK: usize = 50;
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static LAST_COMMAND: RwLock<Option<CommandOutcome>> = RwLock::const_new(None);
+
+/// Remembers when the hub was started to report the uptime
+pub fn init() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Result of the last command that was sent over BLE
+#[derive(Debug, Clone)]
+pub struct CommandOutcome {
+    pub command: Command,
+    pub at: SystemTime,
+    pub result: Result<(), String>,
+}
+
+pub async fn record_command(command: Command, result: &color_eyre::Result<()>) {
+    let outcome = CommandOutcome {
+        command,
+        at: SystemTime::now(),
+        result: result.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+    };
+    *LAST_COMMAND.write().await = Some(outcome);
+}
+
+/// Snapshot of the state of the car and the hub, sent as reply to
+/// [`crate::schema::QueryCommand::Status`]
+#[derive(Debug)]
+pub struct Status {
+    pub engine: EngineStatus,
+    /// `None` if the starter could not be asked
+    pub key: Option<KeyState>,
+    pub starter_connected: bool,
+    pub door_controller_connected: bool,
+    pub starter_health: DeviceHealth,
+    pub starter_diagnostics: Option<StarterDiagnostics>,
+    pub door_controller_health: DeviceHealth,
+    pub last_command: Option<CommandOutcome>,
+    /// Most recent SMS that was not executed
+    pub last_rejected: Option<AuditEntry>,
+    /// Commands waiting for a device
+    pub queued: Vec<QueuedCommand>,
+    /// Firmware update that is currently running
+    pub update: Option<Progress>,
+    pub last_update: Option<UpdateOutcome>,
+    pub battery: Option<u8>,
+    pub uptime: Duration,
+}
+
+impl Status {
+    pub async fn collect() -> Self {
+        let starter_connected = match STARTER.read().await.as_ref() {
+            Some(starter) => starter.is_connected().await.unwrap_or(false),
+            None => false,
+        };
+        let door_controller_connected =
+            match DOOR_CONTROLLER.read().await.as_ref() {
+                Some(door_controller) => {
+                    door_controller.is_connected().await.unwrap_or(false)
+                }
+                None => false,
+            };
+        let battery =
+            match tokio::fs::read_to_string(BATTERY_CAPACITY_PATH).await {
+                Ok(capacity) => capacity.trim().parse().ok(),
+                Err(e) => {
+                    warn!("Failed to read battery capacity: {e}");
+                    None
+                }
+            };
+        let last_rejected =
+            tokio::task::spawn_blocking(|| audit::recent(REJECTED_LOOKBACK))
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .find(|entry| {
+                    !matches!(
+                        entry.decision,
+                        PolicyDecision::Accepted | PolicyDecision::Setup
+                    )
+                });
+        Self {
+            engine: engine::get().await,
+            key: ble::read_key_state()
+                .await
+                .map_err(|e| warn!("Failed to read key state: {e}"))
+                .ok(),
+            starter_connected,
+            door_controller_connected,
+            starter_health: health::get(Device::Starter),
+            starter_diagnostics: ble::read_diagnostics()
+                .await
+                .map_err(|e| warn!("Failed to read starter diagnostics: {e}"))
+                .ok(),
+            door_controller_health: health::get(Device::DoorController),
+            last_command: LAST_COMMAND.read().await.to_owned(),
+            last_rejected,
+            queued: queue::snapshot(),
+            update: ota::progress(),
+            last_update: ota::last_update(),
+            battery,
+            uptime: STARTED
+                .get()
+                .map(Instant::elapsed)
+                .unwrap_or(Duration::ZERO),
+        }
+    }
+}
+
+fn connection(connected: bool) -> &'static str {
+    if connected {
+        "connected"
+    } else {
+        "disconnected"
+    }
+}
+
+/// Names of the common `SocResetReason`s of the ESP32-C3
+fn reset_reason(reason: Option<u8>) -> &'static str {
+    match reason {
+        Some(0x01) => "power on",
+        Some(0x03) => "software",
+        Some(0x07 | 0x08) => "watchdog",
+        Some(0x09 | 0x10) => "rtc watchdog",
+        Some(0x0f) => "brown out",
+        Some(0x12) => "super watchdog",
+        Some(_) => "other",
+        None => "unknown",
+    }
+}
+
+/// Link metrics appended to the connection state
+struct Link<'a>(&'a DeviceHealth);
+
+impl Display for Link<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(rssi) = self.0.rssi {
+            write!(f, ", {rssi}dBm")?;
+        }
+        if let Some(since) = self.0.connected_since {
+            write!(f, ", up {}", Compact(since.elapsed()))?;
+        }
+        if self.0.reconnects > 0 {
+            write!(f, ", {} reconn", self.0.reconnects)?;
+        }
+        if let Some(latency) = self.0.last_write_latency {
+            write!(f, ", {}ms", latency.as_millis())?;
+        }
+        if let Some(panic) = &self.0.last_panic {
+            // the backtrace is only useful in the alert
+            let message = panic.lines().next().unwrap_or_default();
+            write!(f, ", crashed: {message}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats durations as compact as possible to fit into a single SMS
+struct Compact(Duration);
+
+impl Display for Compact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        match secs {
+            0..60 => write!(f, "{secs}s"),
+            60..3600 => write!(f, "{}m", secs / 60),
+            3600..86400 => write!(f, "{}h{}m", secs / 3600, secs % 3600 / 60),
+            _ => write!(f, "{}d{}h", secs / 86400, secs % 86400 / 3600),
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.engine {
+            EngineStatus::Known { state, source, at } => writeln!(
+                f,
+                "engine: {state:?} ({source:?} {} ago)",
+                Compact(at.elapsed())
+            )?,
+            EngineStatus::Unknown => writeln!(f, "engine: unknown")?,
+        }
+        if let Some(key) = &self.key {
+            write!(f, "key: {:?}, {:?}", key.position, key.arbitration)?;
+            if key.occupied() {
+                write!(f, ", someone is in the car")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "starter: {}{}",
+            connection(self.starter_connected),
+            Link(&self.starter_health)
+        )?;
+        if let Some(diagnostics) = &self.starter_diagnostics {
+            let counters = &diagnostics.counters;
+            writeln!(
+                f,
+                "starter fw {} up {}, reset {}, {} boots, {} panics",
+                diagnostics.firmware_version,
+                Compact(Duration::from_secs(diagnostics.uptime_secs)),
+                reset_reason(diagnostics.reset_reason),
+                counters.boots,
+                counters.panics
+            )?;
+            write!(
+                f,
+                "relays {}/{}/{}/{}",
+                counters.radio,
+                counters.engine,
+                counters.engine_consumers,
+                counters.ignition
+            )?;
+            if let Some(anomaly) = &diagnostics.last_key_anomaly {
+                write!(
+                    f,
+                    ", {} key anomalies, last {:?}->{:?} at {}",
+                    diagnostics.key_anomalies,
+                    anomaly.from,
+                    anomaly.to,
+                    Compact(Duration::from_secs(anomaly.uptime_secs))
+                )?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "door: {}{}",
+            connection(self.door_controller_connected),
+            Link(&self.door_controller_health)
+        )?;
+        match &self.last_command {
+            Some(last) => {
+                let ago = last.at.elapsed().unwrap_or_default();
+                let result = match &last.result {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("failed: {e}"),
+                };
+                writeln!(
+                    f,
+                    "last: {:?} {} ago, {result}",
+                    last.command,
+                    Compact(ago)
+                )?;
+            }
+            None => writeln!(f, "last: none")?,
+        }
+        if let Some(rejected) = &self.last_rejected {
+            let ago = (SystemTime::UNIX_EPOCH
+                + Duration::from_secs(rejected.received_at))
+            .elapsed()
+            .unwrap_or_default();
+            writeln!(
+                f,
+                "rejected: {} {} ago, {:?}",
+                rejected.number,
+                Compact(ago),
+                rejected.decision
+            )?;
+        }
+        if !self.queued.is_empty() {
+            let queued = self
+                .queued
+                .iter()
+                .map(|queued| {
+                    format!(
+                        "{:?} {}",
+                        queued.command,
+                        Compact(queued.remaining)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "queued: {queued}")?;
+        }
+        if let Some(update) = &self.update {
+            writeln!(
+                f,
+                "update: {:?} {}%",
+                update.device,
+                u64::from(update.written) * 100 / u64::from(update.size.max(1))
+            )?;
+        }
+        if let Some(last) = &self.last_update {
+            let ago = last.at.elapsed().unwrap_or_default();
+            let result = match &last.result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("failed: {e}"),
+            };
+            writeln!(
+                f,
+                "last update: {:?} {} ago, {result}",
+                last.device,
+                Compact(ago)
+            )?;
+        }
+        match self.battery {
+            Some(battery) => writeln!(f, "battery: {battery}%")?,
+            None => writeln!(f, "battery: unknown")?,
+        }
+        write!(f, "uptime: {}", Compact(self.uptime))
+    }
+}
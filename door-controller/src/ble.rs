@@ -3,22 +3,26 @@ use core::{
     future::Future,
 };
 
-use alloc::borrow::ToOwned;
-use embassy_embedded_hal::adapter::BlockingAsync;
 use embassy_futures::join::join;
-use embedded_io::Write;
+use embassy_time::Timer;
 use esp_hal::rng::Trng;
-use esp_storage::FlashStorage;
+use firmware_auth::{self as auth, Challenge, CHALLENGE_LEN};
+use firmware_image::Target;
+use firmware_ota::{self as ota, Next, Ota};
+use firmware_panic as panic;
+use firmware_storage::Bond;
 use log::{debug, error, info, warn};
-use sequential_storage::{
-    cache::NoCache,
-    map::{fetch_item, store_item, Key, SerializationError, Value},
+use trouble_host::{
+    att::{Att, AttRsp, AttServer},
+    prelude::*,
+    BondInformation, IdentityResolvingKey, LongTermKey,
 };
-use trouble_host::{prelude::*, BondInformation, IdentityResolvingKey, LongTermKey};
 
 use crate::{
     schema::{Lock, WindowLeft, WindowRight},
-    CONTROLLER_CHANNEL, MAP_FLASH_RANGE,
+    settings,
+    storage::{self, Flash},
+    CONTROLLER_CHANNEL,
 };
 
 /// Max number of connections
@@ -29,16 +33,22 @@ const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 
 //const MAX_ATTRIBUTES: usize = 10;
 
-pub const ADDRESS: [u8; 6] = [0xB7, 0x98, 0x49, 0x4E, 0x0D, 0x17];
+/// 32 random bytes shared with the hub, see [`auth`]
+const PAIRING_KEY: &[u8; 32] = include_bytes!(env!("PAIRING_KEY_PATH"));
+
+/// Raw Ed25519 public key the OTA images have to be signed with
+const OTA_PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("OTA_PUBLIC_KEY_PATH"));
 
-// if too long it will leak into the advertisement packets
-const BLE_DEVICE_NAME: &str = "DCtrl";
+pub const ADDRESS: [u8; 6] = [0xB7, 0x98, 0x49, 0x4E, 0x0D, 0x17];
 
 // Service UUID for Door Controller
 pub const DOOR_SERVICE_UUID: u128 = 0x5eb5b1175231409ea1cab7689f488473;
 
-// Used to initiate OTA update boot
-// pub const OTA_CHAR_UUID: u128 = 0xe32a319fcfa44838aac359fde6058ee1;
+// Same OTA service as on the starter, see [`ota`]
+pub const OTA_SERVICE_UUID: u128 = 0xc6422fa864b3485db9c39d86fee29183;
+pub const OTA_CONTROL_CHAR_UUID: u128 = 0xe32a319fcfa44838aac359fde6058ee1;
+pub const OTA_DATA_CHAR_UUID: u128 = 0x52c97e61f1004892ae26d98e8b2bacbf;
+pub const OTA_STATUS_CHAR_UUID: u128 = 0xffb703e1455c486ba902d4a15bbad0fb;
 
 // relay 1 and 2 and 3
 // 1 GPIO32
@@ -59,6 +69,15 @@ pub const WINDOW_LEFT_CHAR_UUID: u128 = 0xb163c9c8b1ac445a8232b7b462bf6b91;
 // Recv only
 pub const WINDOW_RIGHT_CHAR_UUID: u128 = 0x8f738eeebbb74cce8b82726a56532bdc;
 
+// Last panic as text, cleared by writing anything
+pub const PANIC_CHAR_UUID: u128 = 0x486d3e6952ac4bf8b338c1e15d171026;
+
+// Encoded like the stored [`settings::Settings`] record
+pub const SETTINGS_CHAR_UUID: u128 = 0x7d0c94e2b3a54f1e8e6a51c2f09b3d48;
+
+// Same as on the starter, see [`auth`]
+pub const AUTH_CHAR_UUID: u128 = 0x92f50f10b2a84da1bcb743493b4fa736;
+
 #[gatt_service(uuid = DOOR_SERVICE_UUID)]
 struct DoorControllerService {
     #[characteristic(uuid = LOCK_CHAR_UUID, write)]
@@ -67,17 +86,36 @@ struct DoorControllerService {
     window_left: WindowLeft,
     #[characteristic(uuid = WINDOW_RIGHT_CHAR_UUID, write)]
     window_right: WindowRight,
+    #[characteristic(uuid = PANIC_CHAR_UUID, read, write)]
+    panic_record: heapless::Vec<u8, { panic::REPORT_LEN }>,
+    #[characteristic(uuid = SETTINGS_CHAR_UUID, read, write)]
+    settings: heapless::Vec<u8, { settings::MAX_SIZE }>,
+    /// Challenge while not bonded, empty if authorized, takes the sealed passkey
+    #[characteristic(uuid = AUTH_CHAR_UUID, read, write)]
+    auth: heapless::Vec<u8, { auth::SEALED_PASSKEY_LEN }>,
+}
+
+#[gatt_service(uuid = OTA_SERVICE_UUID)]
+struct OtaService {
+    #[characteristic(uuid = OTA_CONTROL_CHAR_UUID, write)]
+    control: heapless::Vec<u8, { ota::CONTROL_MAX_SIZE }>,
+    #[characteristic(uuid = OTA_DATA_CHAR_UUID, write)]
+    data: heapless::Vec<u8, { ota::DATA_MAX_SIZE }>,
+    /// postcard encoded [`ota::OtaStatus`]
+    #[characteristic(uuid = OTA_STATUS_CHAR_UUID, read)]
+    status: heapless::Vec<u8, { ota::STATUS_MAX_SIZE }>,
 }
 
 #[gatt_server]
 struct Server<'a> {
     door_controller: DoorControllerService,
+    ota_service: OtaService,
 }
 
 pub async fn run<C: Controller>(
     controller: C,
     mut rng: Trng<'_>,
-    mut flash: BlockingAsync<FlashStorage>,
+    mut flash: Flash,
 ) -> Result<(), Error> {
     let address = Address::random(ADDRESS);
 
@@ -85,7 +123,9 @@ pub async fn run<C: Controller>(
         HostResources::new();
     let stack = trouble_host::new(controller, &mut resources)
         .set_random_address(address)
-        .set_random_generator_seed(&mut rng);
+        .set_random_generator_seed(&mut rng)
+        // the passkey shown by the phone is entered by the hub, see [`auth`]
+        .set_io_capabilities(IoCapabilities::KeyboardOnly);
 
     let Host {
         mut peripheral,
@@ -98,17 +138,22 @@ pub async fn run<C: Controller>(
         stack.add_bond_information(bond_info)?;
     }
 
+    let settings = settings::get();
     let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: BLE_DEVICE_NAME,
+        name: &settings.ble_name,
         appearance: &trouble_host::prelude::appearance::control_device::GENERIC_CONTROL_DEVICE,
     }))
     .map_err(|_| Error::Other)?;
+    // kept across connections to resume interrupted uploads
+    let mut ota = Ota::new(&mut flash, Target::DoorController, OTA_PUBLIC_KEY).await;
 
     let _ = join(log_error("ble_task", ble_task(runner)), async {
         loop {
-            match advertise_task(&mut peripheral, &server).await {
+            match advertise_task(&mut peripheral, &server, &settings.ble_name).await {
                 Ok(conn) => {
-                    if let Err(e) = gatt_task(&server, &conn, &stack, &mut flash).await {
+                    if let Err(e) =
+                        gatt_task(&server, &conn, &stack, &mut flash, &mut ota, &mut rng).await
+                    {
                         log::error!("Gatt task error: {e:#?}")
                     }
                 }
@@ -142,18 +187,66 @@ async fn gatt_task(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, DefaultPacketPool>,
     stack: &Stack<'_, impl Controller, DefaultPacketPool>,
-    flash: &mut BlockingAsync<FlashStorage>,
+    flash: &mut Flash,
+    ota: &mut Ota,
+    rng: &mut Trng<'_>,
 ) -> Result<(), Error> {
     info!("gatt task running");
     let lock_state = &server.door_controller.lock;
     let window_left_state = &server.door_controller.window_left;
     let window_right_state = &server.door_controller.window_right;
+    let panic_record = &server.door_controller.panic_record;
+    let settings_char = &server.door_controller.settings;
+    let ota_control = &server.ota_service.control;
+    let ota_data = &server.ota_service.data;
+    let ota_status = &server.ota_service.status;
+    let auth_char = &server.door_controller.auth;
     let controller_sender = CONTROLLER_CHANNEL.get().await.sender();
+    // a stored bond was paired with the passkey, the encryption proves that
+    // the peer also holds its keys
+    let peer = conn.raw().peer_address();
+    let mut stored = load_bond_info(flash).await;
+    let mut authorized = stored
+        .as_ref()
+        .is_some_and(|bond_info| bond_info.identity.match_address(&peer));
+    if stored.is_none() {
+        conn.raw().set_bondable(true)?;
+    }
+    let mut random = [0u8; CHALLENGE_LEN];
+    rng.read(&mut random);
+    let challenge = Challenge::new(random);
+    // sealed passkey the hub wrote before the stack asked for it
+    let mut passkey: Option<u32> = None;
+    let mut passkey_requested = false;
     loop {
-        match conn.next().await {
-            GattConnectionEvent::Gatt { event } => match event? {
+        let event = conn.next().await;
+        // a new image that accepts an authorized connection works well enough
+        if encrypted(conn) && authorized {
+            ota.confirm(flash).await;
+        }
+        match event {
+            GattConnectionEvent::Gatt { event } => match event {
                 GattEvent::Read(read) => {
-                    if conn.raw().encrypted() {
+                    if (encrypted(conn) || stored.is_none()) && read.handle() == auth_char.handle {
+                        let value = if authorized {
+                            heapless::Vec::new()
+                        } else {
+                            heapless::Vec::from_slice(challenge.bytes()).expect("challenge fits")
+                        };
+                        server.set(auth_char, &value)?;
+                        read.accept()?.send().await;
+                        if !encrypted(conn) {
+                            // the hub seals the passkey of the pairing started
+                            // here with the challenge
+                            if let Err(e) = conn.raw().request_security() {
+                                warn!("Requesting the pairing failed: {e:?}");
+                            }
+                        }
+                    } else if encrypted(conn) && !authorized {
+                        read.reject(AttErrorCode::INSUFFICIENT_AUTHORISATION)?
+                            .send()
+                            .await;
+                    } else if encrypted(conn) {
                         let forbidden = [
                             lock_state.handle,
                             window_left_state.handle,
@@ -165,6 +258,16 @@ async fn gatt_task(
                                 .await;
                             continue;
                         }
+                        if read.handle() == panic_record.handle {
+                            server.set(panic_record, &panic::report())?;
+                            read.accept()?.send().await;
+                        } else if read.handle() == ota_status.handle {
+                            server.set(ota_status, &ota.status())?;
+                            read.accept()?.send().await;
+                        } else if read.handle() == settings_char.handle {
+                            server.set(settings_char, &settings::encode())?;
+                            read.accept()?.send().await;
+                        }
                     } else {
                         read.reject(AttErrorCode::INSUFFICIENT_ENCRYPTION)?
                             .send()
@@ -172,8 +275,67 @@ async fn gatt_task(
                     }
                 }
                 GattEvent::Write(event) => {
-                    if conn.raw().encrypted() {
-                        if event.handle() == lock_state.handle {
+                    if stored.is_none() && event.handle() == auth_char.handle {
+                        match challenge.open_passkey(PAIRING_KEY, event.data()) {
+                            Some(key) => {
+                                info!("Got the sealed passkey");
+                                event.accept()?.send().await;
+                                if passkey_requested {
+                                    input_passkey(conn, key);
+                                } else {
+                                    passkey = Some(key);
+                                }
+                            }
+                            None => {
+                                warn!("Rejected wrong sealed passkey");
+                                event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?.send().await;
+                            }
+                        }
+                    } else if encrypted(conn) && !authorized {
+                        warn!("Write rejected because the bond is not authorized");
+                        event
+                            .reject(AttErrorCode::INSUFFICIENT_AUTHORISATION)?
+                            .send()
+                            .await;
+                    } else if encrypted(conn) {
+                        if event.handle() == ota_control.handle {
+                            match ota.control(flash, event.data()).await {
+                                Ok(next) => {
+                                    event.accept()?.send().await;
+                                    if next == Next::Reboot {
+                                        info!("Rebooting into the new image");
+                                        // give the response some time to be sent
+                                        Timer::after_millis(500).await;
+                                        esp_hal::system::software_reset();
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!("Rejected OTA control write: {e:?}");
+                                    event.reject(att_error(e as u8))?.send().await;
+                                }
+                            }
+                        } else if event.handle() == ota_data.handle {
+                            match ota.write(flash, event.data()).await {
+                                Ok(()) => event.accept()?.send().await,
+                                Err(e) => {
+                                    warn!("Rejected OTA data write: {e:?}");
+                                    event.reject(att_error(e as u8))?.send().await;
+                                }
+                            }
+                        } else if event.handle() == settings_char.handle {
+                            match settings::update(flash, event.data()).await {
+                                Ok(()) => event.accept()?.send().await,
+                                Err(e) => {
+                                    warn!("Rejected settings: {e:?}");
+                                    event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?.send().await;
+                                }
+                            }
+                        } else if event.handle() == panic_record.handle {
+                            info!("Panic record read by client, clearing it");
+                            panic::clear();
+                            server.set(panic_record, &heapless::Vec::new())?;
+                            event.accept()?.send().await;
+                        } else if event.handle() == lock_state.handle {
                             match Lock::from_gatt(event.data()) {
                                 Ok(val) => controller_sender.send(val.into()).await,
                                 Err(_) => {
@@ -219,41 +381,98 @@ async fn gatt_task(
                             .await;
                     }
                 }
+                GattEvent::Other(event) => event.accept()?.send().await,
             },
             GattConnectionEvent::Disconnected { reason } => {
                 log::warn!("Disconnected: {reason:?}");
                 break;
             }
-            GattConnectionEvent::Bonded { bond_info } => {
-                log::info!("Bonding with new device: {bond_info:x?}");
-                if load_bond_info(flash).await.is_none() {
-                    stack.add_bond_information(bond_info.clone())?;
-                    if bond_info.identity.irk.is_some() {
-                        store_bond_info(flash, bond_info).await;
-                    }
-                    log::info!("Stored bond");
-                } else {
+            GattConnectionEvent::PassKeyInput => match passkey.take() {
+                Some(key) => input_passkey(conn, key),
+                None => {
+                    info!("Waiting for the hub to send the passkey");
+                    passkey_requested = true;
+                }
+            },
+            GattConnectionEvent::PairingComplete {
+                security_level,
+                bond,
+            } => {
+                let identity = conn.raw().peer_identity();
+                log::info!("Paired with {identity:x?}: {security_level:?}");
+                // the stack stored the keys of every pairing, only the first
+                // one with the passkey of the hub is kept
+                if let Some(stored) = stored.take() {
                     warn!(
-                        "Ignored bond from {:x?} since already bonded",
-                        bond_info.identity.bd_addr
+                        "Ignored pairing with {:x?} since already bonded",
+                        identity.bd_addr
                     );
-                    if let Err(e) = stack.remove_bond_information(bond_info.identity) {
+                    if stored.identity.match_identity(&identity) {
+                        // the stack replaced the keys of the stored bond with
+                        // the ones of the spoofing device, removing it would
+                        // drop the bond of the hub
+                        if let Err(e) = stack.add_bond_information(stored) {
+                            error!("Failed to restore the stored bond: {e:?}");
+                        }
+                    } else if let Err(e) = stack.remove_bond_information(identity) {
                         error!("Failed to remove excessive bond: {e:?}");
                     }
                     debug!("Bonds: {:x?}", stack.get_bond_information());
                     conn.raw().disconnect();
                     break;
                 }
+                match bond {
+                    Some(bond_info) if security_level.authenticated() => {
+                        info!("Bond authorized");
+                        authorized = true;
+                        store_bond_info(flash, bond_info.clone()).await;
+                        stored = Some(bond_info);
+                    }
+                    _ => {
+                        warn!("Rejected pairing without the passkey");
+                        if let Err(e) = stack.remove_bond_information(identity) {
+                            error!("Failed to remove the unauthorized bond: {e:?}");
+                        }
+                        conn.raw().disconnect();
+                        break;
+                    }
+                }
             }
+            GattConnectionEvent::PairingFailed(e) => warn!("Pairing failed: {e:?}"),
             _ => log::warn!("unhandled connection event"),
         }
     }
     Ok(())
 }
 
+fn encrypted(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> bool {
+    conn.raw()
+        .security_level()
+        .is_ok_and(|level| level.encrypted())
+}
+
+/// ATT error with an application `code`, `trouble-host` only has constants for
+/// the codes of the specification
+fn att_error(code: u8) -> AttErrorCode {
+    /// Opcode of an ATT error response
+    const ATT_ERROR_RSP: u8 = 0x01;
+    // decoding an error response is the only public way to construct the others
+    match Att::decode(&[ATT_ERROR_RSP, 0, 0, 0, code]) {
+        Ok(Att::Server(AttServer::Response(AttRsp::Error { code, .. }))) => code,
+        _ => AttErrorCode::UNLIKELY_ERROR,
+    }
+}
+
+fn input_passkey(conn: &GattConnection<'_, '_, DefaultPacketPool>, passkey: u32) {
+    if let Err(e) = conn.pass_key_input(passkey) {
+        warn!("Entering the passkey failed: {e:?}");
+    }
+}
+
 async fn advertise_task<'a, 'b, C: Controller>(
     peripheral: &mut Peripheral<'a, C, DefaultPacketPool>,
     server: &'b Server<'_>,
+    name: &str,
 ) -> Result<GattConnection<'a, 'b, DefaultPacketPool>, BleHostError<C::Error>> {
     info!("adv task running");
     let mut adv_data = [0u8; 31];
@@ -267,7 +486,7 @@ async fn advertise_task<'a, 'b, C: Controller>(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
             AdStructure::ServiceUuids128(&[service_uuid]),
-            AdStructure::CompleteLocalName(BLE_DEVICE_NAME.as_bytes()),
+            AdStructure::CompleteLocalName(name.as_bytes()),
         ],
         &mut adv_data[..],
     )?;
@@ -288,112 +507,30 @@ async fn advertise_task<'a, 'b, C: Controller>(
     Ok(conn)
 }
 
-async fn store_bond_info(flash: &mut BlockingAsync<FlashStorage>, bond_info: BondInformation) {
-    let val = BondStoreValue(bond_info);
-    let mut data_buffer = [0u8; 64];
-    log_error(
-        "store bond info",
-        store_item(
-            flash,
-            MAP_FLASH_RANGE,
-            &mut NoCache::new(),
-            &mut data_buffer,
-            &BondStoreKey,
-            &val,
-        ),
-    )
-    .await;
+async fn store_bond_info(flash: &mut Flash, bond_info: BondInformation) {
+    let bond = Bond {
+        long_term_key: bond_info.ltk.0,
+        bd_addr: bond_info.identity.bd_addr.into_inner(),
+        identity_resolving_key: bond_info.identity.irk.map(|irk| irk.0),
+    };
+    storage::store(flash, &bond).await;
+    info!(
+        "Stored bond, IRK present: {}",
+        bond.identity_resolving_key.is_some()
+    );
 }
 
-async fn load_bond_info(flash: &mut BlockingAsync<FlashStorage>) -> Option<BondInformation> {
-    let mut data_buffer = [0u8; 64];
-    let raw: Option<BondStoreValue> = fetch_item(
-        flash,
-        MAP_FLASH_RANGE,
-        &mut NoCache::new(),
-        &mut data_buffer,
-        &BondStoreKey,
-    )
-    .await
-    .map_err(|e| {
-        error!("Failed to load bond info: {e:?}");
-    })
-    .ok()
-    .flatten();
-    raw.map(|v| v.0)
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-struct BondStoreKey;
-
-const BOND_STORE_VALUE_NAME: &str = "BOND";
-
-impl Key for BondStoreKey {
-    fn serialize_into(
-        &self,
-        mut buffer: &mut [u8],
-    ) -> Result<usize, sequential_storage::map::SerializationError> {
-        buffer
-            .write(BOND_STORE_VALUE_NAME.as_bytes())
-            .map_err(|_| SerializationError::InvalidData)
-    }
-    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
-        if buffer.starts_with(BOND_STORE_VALUE_NAME.as_bytes()) {
-            Ok((Self, BOND_STORE_VALUE_NAME.len()))
-        } else {
-            Err(SerializationError::InvalidData)
-        }
-    }
-
-    fn get_len(_: &[u8]) -> Result<usize, SerializationError> {
-        Ok(BOND_STORE_VALUE_NAME.len())
-    }
-}
-
-#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
-struct BondInfoRaw {
-    long_term_key: u128,
-    bd_addr: [u8; 6],
-    identity_resolving_key: u128,
-}
-
-impl From<BondInformation> for BondInfoRaw {
-    fn from(value: BondInformation) -> Self {
-        Self {
-            long_term_key: value.ltk.0,
-            bd_addr: value.identity.bd_addr.into_inner(),
-            identity_resolving_key: value.identity.irk.expect("must ensure irk present").0,
-        }
-    }
-}
-
-impl From<BondInfoRaw> for BondInformation {
-    fn from(value: BondInfoRaw) -> Self {
-        BondInformation {
-            ltk: LongTermKey::new(value.long_term_key),
-            identity: Identity {
-                bd_addr: BdAddr::new(value.bd_addr),
-                irk: Some(IdentityResolvingKey::new(value.identity_resolving_key)),
-            },
-        }
-    }
-}
-
-struct BondStoreValue(BondInformation);
-
-impl<'d> Value<'d> for BondStoreValue {
-    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        let raw: BondInfoRaw = self.0.to_owned().into();
-        postcard::to_slice(&raw, buffer)
-            .map_err(|_| SerializationError::InvalidFormat)
-            .map(|s| s.len())
-    }
-    fn deserialize_from(buffer: &'d [u8]) -> Result<Self, SerializationError>
-    where
-        Self: Sized,
-    {
-        let raw: BondInfoRaw =
-            postcard::from_bytes(buffer).map_err(|_| SerializationError::InvalidFormat)?;
-        Ok(Self(raw.into()))
-    }
+async fn load_bond_info(flash: &mut Flash) -> Option<BondInformation> {
+    let bond: Bond = storage::fetch(flash).await?;
+    // only bonds paired with the passkey are stored, older firmwares stored
+    // them once the hub proved the pairing key
+    Some(BondInformation::new(
+        Identity {
+            bd_addr: BdAddr::new(bond.bd_addr),
+            irk: bond.identity_resolving_key.map(IdentityResolvingKey::new),
+        },
+        LongTermKey::new(bond.long_term_key),
+        SecurityLevel::EncryptedAuthenticated,
+        true,
+    ))
 }
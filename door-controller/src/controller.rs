@@ -5,7 +5,10 @@ use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Receiver};
 use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio::Output;
 
-use crate::schema::{Lock, WindowLeft, WindowRight};
+use crate::{
+    schema::{Lock, WindowLeft, WindowRight},
+    settings,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operation {
@@ -116,12 +119,15 @@ impl Controller<'_> {
         operation: Operation,
         queue: &mut BinaryHeap<Reverse<WaitCompleteOperation>>,
     ) {
+        let settings = settings::get();
+        let lock_pulse = Duration::from_millis(settings.lock_pulse_ms.into());
+        let window_run = Duration::from_millis(settings.window_run_ms.into());
         match operation {
             Operation::DoorClose => {
                 self.door_disconnect.set_high();
                 self.door_close.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(1),
+                    wake_up: Instant::now() + lock_pulse,
                     operation,
                 }));
             }
@@ -129,35 +135,35 @@ impl Controller<'_> {
                 self.door_disconnect.set_high();
                 self.door_open.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(1),
+                    wake_up: Instant::now() + lock_pulse,
                     operation,
                 }));
             }
             Operation::WindowLeftUp => {
                 self.window_left_up.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(5),
+                    wake_up: Instant::now() + window_run,
                     operation,
                 }));
             }
             Operation::WindowLeftDown => {
                 self.window_left_down.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(5),
+                    wake_up: Instant::now() + window_run,
                     operation,
                 }));
             }
             Operation::WindowRightUp => {
                 self.window_right_up.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(5),
+                    wake_up: Instant::now() + window_run,
                     operation,
                 }));
             }
             Operation::WindowRightDown => {
                 self.window_right_down.set_high();
                 queue.push(Reverse(WaitCompleteOperation {
-                    wake_up: Instant::now() + Duration::from_secs(5),
+                    wake_up: Instant::now() + window_run,
                     operation,
                 }));
             }
@@ -7,8 +7,7 @@ use core::ops::Range;
 
 use bt_hci::controller::ExternalController;
 use controller::{Controller, Operation};
-use embassy_embedded_hal::adapter::BlockingAsync;
-use embassy_futures::join::join;
+use embassy_futures::join::join3;
 use embassy_sync::{
     blocking_mutex::raw::NoopRawMutex,
     channel::{self, Channel},
@@ -20,13 +19,15 @@ use esp_hal::{
     rng::Trng,
     timer::timg::TimerGroup,
 };
-use esp_storage::FlashStorage;
 use esp_wifi::ble::controller::BleConnector;
+use firmware_hci::Hci;
 use log::debug;
 
 mod ble;
 mod controller;
 mod schema;
+mod settings;
+mod storage;
 
 // von links oben bei power connector nach unten relays
 // auch hier zu entnehmen <https://devices.esphome.io/devices/ESP32E-Relay-X8>
@@ -38,8 +39,8 @@ mod schema;
 // 6 GPIO14
 // 8 GPIO13
 
-// creds,    data, nvs,     0x110000, 0x2000,
-pub const MAP_FLASH_RANGE: Range<u32> = 0x110000..(0x110000 + 0x2000);
+// creds,    data, nvs,     0x210000, 0x2000,
+pub const MAP_FLASH_RANGE: Range<u32> = 0x210000..(0x210000 + 0x2000);
 
 pub static CONTROLLER_CHANNEL: OnceLock<Channel<NoopRawMutex, Operation, 10>> = OnceLock::new();
 #[esp_hal_embassy::main]
@@ -65,12 +66,13 @@ async fn main(_spawner: embassy_executor::Spawner) {
 
     esp_hal_embassy::init(timg0.timer0);
 
-    let flash = FlashStorage::new();
-    let flash = BlockingAsync::new(flash);
+    let mut flash = storage::open().await;
+    firmware_ota::check_boot(&mut flash).await;
+    settings::init(&mut flash).await;
 
     let bluetooth = peripherals.BT;
     let connector = BleConnector::new(&init, bluetooth);
-    let ble_controller: ExternalController<_, 20> = ExternalController::new(connector);
+    let ble_controller: ExternalController<_, 20> = ExternalController::new(Hci::new(connector));
 
     let config = OutputConfig::default()
         .with_pull(Pull::None)
@@ -97,15 +99,26 @@ async fn main(_spawner: embassy_executor::Spawner) {
         window_right_down,
     };
 
-    let (_door, ble) = join(controller.run(), ble::run(ble_controller, trng, flash)).await;
+    let (_door, ble, _) = join3(
+        controller.run(),
+        ble::run(ble_controller, trng, flash),
+        firmware_ota::confirm_timeout(),
+    )
+    .await;
     match ble {
         Ok(()) => log::info!("BLE returned with Ok"),
         Err(e) => log::error!("BLE returned with error: {e:#?}"),
     }
 }
 
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    firmware_panic::handle(info, || {})
+}
+
 #[no_mangle]
 extern "Rust" fn custom_halt() {
     log::error!("Paniced, resetting...");
+    firmware_panic::record_exception();
     esp_hal::system::software_reset();
 }
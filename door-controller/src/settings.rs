@@ -0,0 +1,89 @@
+//! Tunables that can be changed over BLE without flashing a new image
+//!
+//! The settings are persisted next to the bond record, see
+//! [`firmware_storage::settings`].
+
+use firmware_storage::{
+    settings::{self as stored, Current, UpdateError},
+    Record, RecordKey,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Flash;
+
+/// Longer names do not fit into the advertisement next to the service uuid
+pub const NAME_MAX_LEN: usize = 8;
+/// Size of the encoded settings
+pub const MAX_SIZE: usize = 32;
+/// The hub keeps the door controller powered for 3 s for the lock
+const LOCK_PULSE_MAX_MS: u16 = 2_000;
+/// and for 10 s for the windows
+const WINDOW_RUN_MAX_MS: u16 = 9_000;
+
+static SETTINGS: Current<Settings> = Current::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    /// How long the lock relays are powered
+    pub lock_pulse_ms: u16,
+    /// How long the window relays are powered
+    pub window_run_ms: u16,
+    /// Advertised name, takes effect after a reset
+    pub ble_name: heapless::String<NAME_MAX_LEN>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            lock_pulse_ms: 1000,
+            window_run_ms: 5000,
+            // if too long it will leak into the advertisement packets
+            ble_name: heapless::String::try_from("DCtrl").expect("name fits"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InvalidSettings {
+    LockPulse,
+    WindowRun,
+    Name,
+}
+
+impl stored::Settings for Settings {
+    type Invalid = InvalidSettings;
+
+    fn validate(&self) -> Result<(), InvalidSettings> {
+        if self.lock_pulse_ms == 0 || self.lock_pulse_ms > LOCK_PULSE_MAX_MS {
+            return Err(InvalidSettings::LockPulse);
+        }
+        if self.window_run_ms == 0 || self.window_run_ms > WINDOW_RUN_MAX_MS {
+            return Err(InvalidSettings::WindowRun);
+        }
+        if self.ble_name.is_empty() || !self.ble_name.is_ascii() {
+            return Err(InvalidSettings::Name);
+        }
+        Ok(())
+    }
+}
+
+impl Record for Settings {
+    const KEY: RecordKey = RecordKey::Settings;
+    const VERSION: u8 = 1;
+}
+
+pub fn get() -> Settings {
+    SETTINGS.get()
+}
+
+pub async fn init(flash: &mut Flash) {
+    SETTINGS.init(flash).await;
+}
+
+pub fn encode() -> heapless::Vec<u8, MAX_SIZE> {
+    SETTINGS.encode()
+}
+
+pub async fn update(flash: &mut Flash, data: &[u8]) -> Result<(), UpdateError<InvalidSettings>> {
+    SETTINGS.update(flash, data).await
+}
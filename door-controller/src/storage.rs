@@ -0,0 +1,33 @@
+use embassy_embedded_hal::adapter::BlockingAsync;
+use esp_storage::FlashStorage;
+use firmware_storage::{Record, Store};
+use log::error;
+
+use crate::MAP_FLASH_RANGE;
+
+/// Pages of [`MAP_FLASH_RANGE`]
+const PAGES: usize = 2;
+
+/// Flash holding the records in [`MAP_FLASH_RANGE`]
+pub type Flash = Store<BlockingAsync<FlashStorage>, PAGES>;
+
+pub async fn open() -> Flash {
+    Store::open(BlockingAsync::new(FlashStorage::new()), MAP_FLASH_RANGE).await
+}
+
+pub async fn store<R: Record>(flash: &mut Flash, record: &R) {
+    if let Err(e) = flash.store(record).await {
+        error!("Failed to store {:?}: {e:?}", R::KEY);
+    }
+}
+
+pub async fn fetch<R: Record>(flash: &mut Flash) -> Option<R> {
+    flash
+        .fetch()
+        .await
+        .map_err(|e| {
+            error!("Failed to load {:?}: {e:?}", R::KEY);
+        })
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,132 @@
+//! Keys shared between the hub and the devices
+//!
+//! Devices pair with LE Secure Connections passkey entry. The phone of the hub
+//! shows the passkey and the device has no display or keyboard, so the hub
+//! hands the passkey to the device sealed with the pairing key compiled into
+//! the firmware and the hub, see [`Challenge`]. Only a central that knows the
+//! pairing key can therefore bond with a device. Engine commands are
+//! authenticated on their own, see [`command`].
+//!
+//! Layout of a sealed passkey: the passkey as u32 little endian XORed with the
+//! first four bytes of `HMAC-SHA256(key, "pad" || challenge)`, then the first
+//! [`TAG_LEN`] bytes of `HMAC-SHA256(key, "tag" || challenge || sealed)`.
+#![no_std]
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub mod command;
+
+pub const CHALLENGE_LEN: usize = 16;
+pub const TAG_LEN: usize = 16;
+pub const SEALED_PASSKEY_LEN: usize = 4 + TAG_LEN;
+/// Passkeys are six decimal digits
+const MAX_PASSKEY: u32 = 999_999;
+
+fn mac(key: &[u8; 32], label: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(label);
+    mac.update(challenge);
+    mac
+}
+
+fn pad(key: &[u8; 32], challenge: &[u8]) -> [u8; 4] {
+    let pad = mac(key, b"pad", challenge).finalize().into_bytes();
+    [pad[0], pad[1], pad[2], pad[3]]
+}
+
+fn tag(key: &[u8; 32], challenge: &[u8], sealed: &[u8]) -> Hmac<Sha256> {
+    let mut tag = mac(key, b"tag", challenge);
+    tag.update(sealed);
+    tag
+}
+
+/// Seals the `passkey` the phone shows for the device that sent `challenge`
+pub fn seal_passkey(key: &[u8; 32], challenge: &[u8], passkey: u32) -> [u8; SEALED_PASSKEY_LEN] {
+    let mut sealed = [0u8; SEALED_PASSKEY_LEN];
+    for ((sealed, passkey), pad) in sealed
+        .iter_mut()
+        .zip(passkey.to_le_bytes())
+        .zip(pad(key, challenge))
+    {
+        *sealed = passkey ^ pad;
+    }
+    let tag = tag(key, challenge, &sealed[..4]).finalize().into_bytes();
+    sealed[4..].copy_from_slice(&tag[..TAG_LEN]);
+    sealed
+}
+
+/// Random challenge of one connection
+pub struct Challenge([u8; CHALLENGE_LEN]);
+
+impl Challenge {
+    pub fn new(random: [u8; CHALLENGE_LEN]) -> Self {
+        Self(random)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Passkey [sealed](seal_passkey) for this challenge, the tag is compared
+    /// in constant time
+    pub fn open_passkey(&self, key: &[u8; 32], sealed: &[u8]) -> Option<u32> {
+        if sealed.len() != SEALED_PASSKEY_LEN {
+            return None;
+        }
+        let (passkey, sealed_tag) = sealed.split_at(4);
+        tag(key, &self.0, passkey)
+            .verify_truncated_left(sealed_tag)
+            .ok()?;
+        let mut bytes = [0u8; 4];
+        for ((byte, passkey), pad) in bytes.iter_mut().zip(passkey).zip(pad(key, &self.0)) {
+            *byte = passkey ^ pad;
+        }
+        Some(u32::from_le_bytes(bytes)).filter(|passkey| *passkey <= MAX_PASSKEY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x42; 32];
+    const PASSKEY: u32 = 123_456;
+    /// Computed independently of this crate
+    const SEALED_VECTOR: [u8; SEALED_PASSKEY_LEN] = [
+        0x6c, 0x43, 0x27, 0xf6, 0x7a, 0xa3, 0x9a, 0x9e, 0xaf, 0x68, 0x3d, 0x50, 0xf9, 0x6b, 0x93,
+        0x6f, 0x16, 0x53, 0xd8, 0xe0,
+    ];
+
+    #[test]
+    fn sealed_vector() {
+        let sealed = seal_passkey(&KEY, &[0; CHALLENGE_LEN], PASSKEY);
+        assert_eq!(sealed, SEALED_VECTOR);
+    }
+
+    #[test]
+    fn opens_passkey() {
+        let challenge = Challenge::new([7; CHALLENGE_LEN]);
+        let sealed = seal_passkey(&KEY, challenge.bytes(), PASSKEY);
+        assert_eq!(challenge.open_passkey(&KEY, &sealed), Some(PASSKEY));
+    }
+
+    #[test]
+    fn rejects_wrong_seal() {
+        let challenge = Challenge::new([7; CHALLENGE_LEN]);
+        let mut sealed = seal_passkey(&KEY, challenge.bytes(), PASSKEY);
+        assert_eq!(challenge.open_passkey(&[0; 32], &sealed), None);
+        assert_eq!(challenge.open_passkey(&KEY, &sealed[..8]), None);
+        let other = Challenge::new([8; CHALLENGE_LEN]);
+        assert_eq!(other.open_passkey(&KEY, &sealed), None);
+        sealed[0] ^= 1;
+        assert_eq!(challenge.open_passkey(&KEY, &sealed), None);
+    }
+
+    #[test]
+    fn rejects_long_passkey() {
+        let challenge = Challenge::new([7; CHALLENGE_LEN]);
+        let sealed = seal_passkey(&KEY, challenge.bytes(), 1_000_000);
+        assert_eq!(challenge.open_passkey(&KEY, &sealed), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! HCI transport of `esp-wifi` for the newer `bt-hci` of `trouble-host`
+//!
+//! `esp-wifi` implements the `Transport` trait of `bt-hci` 0.3, but the
+//! `trouble-host` release with passkey entry pairing is built on `bt-hci` 0.6.
+//! The two traits have the same shape, [`Hci`] passes the raw packets from one
+//! to the other.
+#![no_std]
+
+use bt_hci::{
+    transport::Transport, ControllerToHostPacket, FromHciBytes, FromHciBytesError,
+    HostToControllerPacket, PacketKind,
+};
+use bt_hci_esp as esp;
+
+/// Largest packet sent to the controller, without its packet indicator
+pub const MAX_PACKET_LEN: usize = 259;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Transport(E),
+    /// The packet could not be parsed, also if it was not found at the start
+    /// of the read buffer
+    Hci(FromHciBytesError),
+    /// The packet to write is larger than [`MAX_PACKET_LEN`]
+    TooLarge,
+}
+
+impl<E: embedded_io::Error> embedded_io::Error for Error<E> {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        match self {
+            Self::Transport(e) => e.kind(),
+            Self::Hci(_) => embedded_io::ErrorKind::InvalidData,
+            Self::TooLarge => embedded_io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+// `ExternalController` reports invalid events as transport errors
+impl<E> From<FromHciBytesError> for Error<E> {
+    fn from(e: FromHciBytesError) -> Self {
+        Self::Hci(e)
+    }
+}
+
+/// Wraps a `bt-hci` 0.3 transport as a `bt-hci` 0.6 transport
+pub struct Hci<T>(T);
+
+impl<T> Hci<T> {
+    pub fn new(transport: T) -> Self {
+        Self(transport)
+    }
+}
+
+impl<T: esp::transport::Transport> embedded_io::ErrorType for Hci<T> {
+    type Error = Error<T::Error>;
+}
+
+impl<T: esp::transport::Transport> Transport for Hci<T> {
+    async fn read<'a>(&self, rx: &'a mut [u8]) -> Result<ControllerToHostPacket<'a>, Self::Error> {
+        let kind = self.0.read(rx).await.map_err(Error::Transport)?.kind() as u8;
+        // `esp-wifi` copies the whole packet including its indicator to the
+        // start of `rx` and parses it from there, so it can be parsed again.
+        // The kind is compared in case a later version stops doing that.
+        let (packet, _) = ControllerToHostPacket::from_hci_bytes(rx)?;
+        if packet.kind() as u8 != kind {
+            return Err(Error::Hci(FromHciBytesError::InvalidValue));
+        }
+        Ok(packet)
+    }
+
+    async fn write<P: HostToControllerPacket>(&self, val: &P) -> Result<(), Self::Error> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let len = val.size();
+        if len > buf.len() {
+            return Err(Error::TooLarge);
+        }
+        val.write_hci(&mut buf[..]).map_err(|_| Error::TooLarge)?;
+        let bytes = &buf[..len];
+        let written = match P::KIND {
+            PacketKind::Cmd => self.0.write(&Raw::<{ PacketKind::Cmd as u8 }>(bytes)).await,
+            PacketKind::AclData => {
+                self.0
+                    .write(&Raw::<{ PacketKind::AclData as u8 }>(bytes))
+                    .await
+            }
+            PacketKind::SyncData => {
+                self.0
+                    .write(&Raw::<{ PacketKind::SyncData as u8 }>(bytes))
+                    .await
+            }
+            PacketKind::IsoData => {
+                self.0
+                    .write(&Raw::<{ PacketKind::IsoData as u8 }>(bytes))
+                    .await
+            }
+            // Only the controller sends events
+            PacketKind::Event => return Err(Error::Hci(FromHciBytesError::InvalidValue)),
+        };
+        written.map_err(Error::Transport)
+    }
+}
+
+/// Packet of kind `KIND` that was already serialized by `bt-hci` 0.6
+struct Raw<'a, const KIND: u8>(&'a [u8]);
+
+impl<const KIND: u8> esp::WriteHci for Raw<'_, KIND> {
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write_hci<W: embedded_io::Write>(&self, mut writer: W) -> Result<(), W::Error> {
+        writer.write_all(self.0)
+    }
+
+    async fn write_hci_async<W: embedded_io_async::Write>(
+        &self,
+        mut writer: W,
+    ) -> Result<(), W::Error> {
+        writer.write_all(self.0).await
+    }
+}
+
+impl<const KIND: u8> esp::HostToControllerPacket for Raw<'_, KIND> {
+    const KIND: esp::PacketKind = match KIND {
+        1 => esp::PacketKind::Cmd,
+        2 => esp::PacketKind::AclData,
+        3 => esp::PacketKind::SyncData,
+        5 => esp::PacketKind::IsoData,
+        _ => panic!("not a packet sent to the controller"),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::RefCell;
+    use std::vec::Vec;
+
+    use bt_hci::{
+        cmd::controller_baseband::Reset,
+        data::{AclBroadcastFlag, AclPacket, AclPacketBoundary},
+        param::ConnHandle,
+    };
+    use esp::FromHciBytes as _;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Command complete event of a reset
+    const EVENT: [u8; 7] = [0x04, 0x0e, 0x04, 0x01, 0x03, 0x0c, 0x00];
+    /// ACL packet of handle 1 with three bytes
+    const ACL: [u8; 8] = [0x02, 0x01, 0x20, 0x03, 0x00, 1, 2, 3];
+
+    /// Transport reading and writing like `esp-wifi`
+    #[derive(Default)]
+    struct Mock {
+        rx: Vec<u8>,
+        /// Skip the packet indicator when copying to the read buffer
+        headless: bool,
+        tx: RefCell<Vec<(esp::PacketKind, Vec<u8>)>>,
+    }
+
+    impl embedded_io::ErrorType for Mock {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    impl esp::transport::Transport for Mock {
+        async fn read<'a>(
+            &self,
+            rx: &'a mut [u8],
+        ) -> Result<esp::ControllerToHostPacket<'a>, Self::Error> {
+            let len = self.rx.len();
+            let (kind, _) = esp::PacketKind::from_hci_bytes(&self.rx).unwrap();
+            let start = usize::from(self.headless);
+            rx[..len - start].copy_from_slice(&self.rx[start..]);
+            let data = if self.headless { &rx[..] } else { &rx[1..] };
+            esp::ControllerToHostPacket::from_hci_bytes_with_kind(kind, data)
+                .map(|(packet, _)| packet)
+                .map_err(|_| embedded_io::ErrorKind::InvalidData)
+        }
+
+        async fn write<P: esp::HostToControllerPacket>(&self, val: &P) -> Result<(), Self::Error> {
+            let mut buf = [0; MAX_PACKET_LEN];
+            val.write_hci(&mut buf[..])
+                .map_err(|_| embedded_io::ErrorKind::OutOfMemory)?;
+            self.tx
+                .borrow_mut()
+                .push((P::KIND, buf[..val.size()].to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_event() {
+        let hci = Hci::new(Mock {
+            rx: EVENT.to_vec(),
+            ..Default::default()
+        });
+        let mut rx = [0; 64];
+        let packet = block_on(hci.read(&mut rx)).unwrap();
+        assert!(matches!(packet, ControllerToHostPacket::Event(_)));
+    }
+
+    #[test]
+    fn reads_acl() {
+        let hci = Hci::new(Mock {
+            rx: ACL.to_vec(),
+            ..Default::default()
+        });
+        let mut rx = [0; 64];
+        match block_on(hci.read(&mut rx)).unwrap() {
+            ControllerToHostPacket::Acl(acl) => {
+                assert_eq!(acl.handle().raw(), 1);
+                assert_eq!(acl.data(), &[1, 2, 3]);
+            }
+            _ => panic!("not an ACL packet"),
+        }
+    }
+
+    #[test]
+    fn rejects_other_layout() {
+        let hci = Hci::new(Mock {
+            rx: ACL.to_vec(),
+            headless: true,
+            ..Default::default()
+        });
+        let mut rx = [0; 64];
+        assert!(matches!(block_on(hci.read(&mut rx)), Err(Error::Hci(_))));
+    }
+
+    #[test]
+    fn writes_packets() {
+        let hci = Hci::new(Mock::default());
+        block_on(hci.write(&Reset::new())).unwrap();
+        let acl = AclPacket::new(
+            ConnHandle::new(1),
+            AclPacketBoundary::FirstFlushable,
+            AclBroadcastFlag::PointToPoint,
+            &[1, 2, 3],
+        );
+        block_on(hci.write(&acl)).unwrap();
+        let tx = hci.0.tx.into_inner();
+        assert_eq!(tx[0], (esp::PacketKind::Cmd, [0x03, 0x0c, 0x00].to_vec()));
+        assert_eq!(tx[1], (esp::PacketKind::AclData, ACL[1..].to_vec()));
+    }
+}
@@ -0,0 +1,51 @@
+//! Prepends a signed [`Header`] to an app image
+//!
+//! ```sh
+//! sign-image <key.pem> <starter|door-controller> <version> <app image> <output>
+//! ```
+
+use std::{env, fs, process::ExitCode};
+
+use ed25519_dalek::{pkcs8::DecodePrivateKey, SigningKey};
+use firmware_image::{Header, Target};
+
+const USAGE: &str =
+    "usage: sign-image <key.pem> <starter|door-controller> <version> <app image> <output>";
+
+fn run(args: &[String]) -> Result<(), String> {
+    let [key, target, version, image, output] = args else {
+        return Err(USAGE.to_owned());
+    };
+    let pem = fs::read_to_string(key).map_err(|e| format!("Failed to read {key}: {e}"))?;
+    let key = SigningKey::from_pkcs8_pem(&pem).map_err(|e| format!("Invalid key: {e}"))?;
+    let target = match target.as_str() {
+        "starter" => Target::Starter,
+        "door-controller" => Target::DoorController,
+        _ => return Err(format!("Unknown target {target}\n{USAGE}")),
+    };
+    let version = version
+        .parse()
+        .map_err(|e| format!("Invalid version {version}: {e}"))?;
+    let image = fs::read(image).map_err(|e| format!("Failed to read {image}: {e}"))?;
+
+    let header = Header::sign(target, version, &image, &key);
+    let mut file = header.to_bytes().to_vec();
+    file.extend(&image);
+    fs::write(output, file).map_err(|e| format!("Failed to write {output}: {e}"))?;
+    println!(
+        "Signed {target:?} image version {version}, {} bytes",
+        image.len()
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Header of the firmware images installed over the air
+//!
+//! An image file is the [`Header`] followed by the app image as written by
+//! espflash. The header names the device the image is built for and its
+//! version, which has to be at least the highest version installed before so
+//! old images with known bugs cannot be installed again. It is signed with the
+//! Ed25519 key whose public key is compiled into the firmware.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+const MAGIC: [u8; 4] = *b"CFWI";
+const FORMAT_VERSION: u8 = 1;
+/// Part of the header covered by the signature
+const SIGNED_LEN: usize = 64;
+pub const HEADER_LEN: usize = SIGNED_LEN + 64;
+
+/// Device an image is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Target {
+    Starter = 1,
+    DoorController = 2,
+}
+
+impl TryFrom<u8> for Target {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Starter),
+            2 => Ok(Self::DoorController),
+            _ => Err(Error::Target),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Length,
+    Magic,
+    FormatVersion,
+    Target,
+    Signature,
+}
+
+/// Little endian layout:
+///
+/// | offset | content                              |
+/// |--------|--------------------------------------|
+/// | 0      | magic `CFWI`                         |
+/// | 4      | format version                       |
+/// | 5      | [`Target`]                           |
+/// | 8      | version                              |
+/// | 12     | size of the app image                |
+/// | 16     | SHA-256 of the app image             |
+/// | 48     | reserved, zero                       |
+/// | 64     | signature over the first 64 bytes    |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub target: Target,
+    /// Anti-rollback version
+    pub version: u32,
+    pub size: u32,
+    pub digest: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes: &[u8; HEADER_LEN] = bytes
+            .get(..HEADER_LEN)
+            .and_then(|header| header.try_into().ok())
+            .ok_or(Error::Length)?;
+        if bytes[0..4] != MAGIC {
+            return Err(Error::Magic);
+        }
+        if bytes[4] != FORMAT_VERSION {
+            return Err(Error::FormatVersion);
+        }
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Ok(Self {
+            target: bytes[5].try_into()?,
+            version: word(8),
+            size: word(12),
+            digest: bytes[16..48].try_into().unwrap(),
+            signature: bytes[SIGNED_LEN..].try_into().unwrap(),
+        })
+    }
+
+    fn signed_bytes(&self) -> [u8; SIGNED_LEN] {
+        let mut bytes = [0u8; SIGNED_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = FORMAT_VERSION;
+        bytes[5] = self.target as u8;
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes[16..48].copy_from_slice(&self.digest);
+        bytes
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..SIGNED_LEN].copy_from_slice(&self.signed_bytes());
+        bytes[SIGNED_LEN..].copy_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Checks the signature, the image itself is covered by [`Self::digest`]
+    pub fn verify(&self, public_key: &[u8; 32]) -> Result<(), Error> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| Error::Signature)?;
+        key.verify_strict(
+            &self.signed_bytes(),
+            &Signature::from_bytes(&self.signature),
+        )
+        .map_err(|_| Error::Signature)
+    }
+
+    /// Whether `image` is the app image this header was created for
+    pub fn matches(&self, image: &[u8]) -> bool {
+        image.len() == self.size as usize && Sha256::digest(image)[..] == self.digest
+    }
+
+    #[cfg(any(test, feature = "sign"))]
+    pub fn sign(
+        target: Target,
+        version: u32,
+        image: &[u8],
+        key: &ed25519_dalek::SigningKey,
+    ) -> Self {
+        use ed25519_dalek::Signer;
+
+        let mut header = Self {
+            target,
+            version,
+            size: image.len() as u32,
+            digest: Sha256::digest(image).into(),
+            signature: [0; 64],
+        };
+        header.signature = key.sign(&header.signed_bytes()).to_bytes();
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    const IMAGE: &[u8] = b"app image";
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn public_key() -> [u8; 32] {
+        key().verifying_key().to_bytes()
+    }
+
+    fn signed() -> Header {
+        Header::sign(Target::Starter, 3, IMAGE, &key())
+    }
+
+    /// Header of [`IMAGE`] for the starter in version 3, signed with [`key`]
+    const SIGNED_HEADER: [&str; 4] = [
+        "4346574901010000030000000900000003630d7923a02e7d5157ab8937f130ff",
+        "7e736408a0dd051ceb3afc873607213400000000000000000000000000000000",
+        "ff0061b4680b130f7b3995a89b742c8de9a3e51c198ae13eabf06f7dd716558d",
+        "df30e8a111f500a63172ae98f0524f9708391523f81d501e9df0fdca841db802",
+    ];
+
+    fn decode_hex(lines: &[&str]) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        let digits = lines.iter().flat_map(|line| line.as_bytes().chunks(2));
+        for (byte, digits) in bytes.iter_mut().zip(digits) {
+            let digits = core::str::from_utf8(digits).unwrap();
+            *byte = u8::from_str_radix(digits, 16).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_vector() {
+        let header = Header::parse(&decode_hex(&SIGNED_HEADER)).unwrap();
+        assert_eq!(header.target, Target::Starter);
+        assert_eq!(header.version, 3);
+        assert_eq!(header.verify(&public_key()), Ok(()));
+        assert!(header.matches(IMAGE));
+        // signing is deterministic
+        assert_eq!(header, signed());
+    }
+
+    #[test]
+    fn round_trip() {
+        let header = signed();
+        let parsed = Header::parse(&header.to_bytes()).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.verify(&public_key()), Ok(()));
+        assert!(parsed.matches(IMAGE));
+    }
+
+    #[test]
+    fn tampered_header() {
+        let mut bytes = signed().to_bytes();
+        // raise the version
+        bytes[8] += 1;
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.verify(&public_key()), Err(Error::Signature));
+
+        let mut bytes = signed().to_bytes();
+        bytes[HEADER_LEN - 1] ^= 1;
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.verify(&public_key()), Err(Error::Signature));
+    }
+
+    #[test]
+    fn other_key() {
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert_eq!(signed().verify(&other), Err(Error::Signature));
+    }
+
+    #[test]
+    fn tampered_image() {
+        let header = signed();
+        assert!(!header.matches(b"app imagf"));
+        assert!(!header.matches(b"app image "));
+        assert!(!header.matches(&IMAGE[..IMAGE.len() - 1]));
+    }
+
+    #[test]
+    fn invalid_header() {
+        let bytes = signed().to_bytes();
+        assert_eq!(Header::parse(&bytes[..HEADER_LEN - 1]), Err(Error::Length));
+
+        let mut magic = bytes;
+        magic[0] = b'X';
+        assert_eq!(Header::parse(&magic), Err(Error::Magic));
+
+        let mut format = bytes;
+        format[4] = FORMAT_VERSION + 1;
+        assert_eq!(Header::parse(&format), Err(Error::FormatVersion));
+
+        let mut target = bytes;
+        target[5] = 0;
+        assert_eq!(Header::parse(&target), Err(Error::Target));
+    }
+
+    #[test]
+    fn keeps_target() {
+        let header = Header::sign(Target::DoorController, 1, IMAGE, &key());
+        let parsed = Header::parse(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.target, Target::DoorController);
+        assert_ne!(parsed.target, Target::Starter);
+    }
+}
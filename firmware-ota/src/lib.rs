@@ -0,0 +1,576 @@
+//! Over the air updates into the app partition that is not running
+//!
+//! A client announces the image with [`BEGIN`] and its signed
+//! [`firmware_image::Header`], and then writes the app image in order in
+//! chunks. Images for another device or with a version below the
+//! anti-rollback counter are rejected. On [`FINISH`] the written image is
+//! hashed again and the boot partition is only switched in `otadata` if hash
+//! and signature match.
+//!
+//! The new image has to confirm itself by accepting an encrypted connection.
+//! If it is reset before, or does not within [`CONFIRM_TIMEOUT_SECS`], it is
+//! marked invalid and the previous image is booted again. The anti-rollback
+//! counter is only raised to the version of the new image once it confirmed
+//! itself, so a broken image does not lock out the previous one.
+#![no_std]
+
+use core::{
+    convert::Infallible,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_time::Timer;
+use embedded_storage_async::nor_flash::NorFlash;
+use esp_hal::{ram, rom::crc::crc32_le};
+use firmware_image::{Header, Target, HEADER_LEN};
+use firmware_storage::{MinVersion, Store};
+use log::{error, info, warn};
+use postcard::to_slice;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// see partitions.csv
+const OTADATA_OFFSET: u32 = 0xd000;
+const SECTOR_SIZE: u32 = 0x1000;
+const SLOT_OFFSETS: [u32; 2] = [0x10000, 0x110000];
+const SLOT_SIZE: u32 = 0x100000;
+
+// `esp_ota_img_states_t`
+const STATE_NEW: u32 = 0x0;
+const STATE_PENDING_VERIFY: u32 = 0x1;
+const STATE_VALID: u32 = 0x2;
+const STATE_INVALID: u32 = 0x3;
+const STATE_ABORTED: u32 = 0x4;
+
+/// A new image that is not confirmed after this long is rolled back
+const CONFIRM_TIMEOUT_SECS: u64 = 10 * 60;
+
+/// `[BEGIN, header: [u8; HEADER_LEN]]`
+pub const BEGIN: u8 = 1;
+/// `[FINISH]`, verifies the image and boots it
+pub const FINISH: u8 = 2;
+/// `[ABORT]`, discards the upload
+pub const ABORT: u8 = 3;
+
+pub const CONTROL_MAX_SIZE: usize = 1 + HEADER_LEN;
+/// `[offset: u32 LE, data..]`, data has to be a multiple of 4 bytes except for
+/// the last chunk
+pub const DATA_MAX_SIZE: usize = 252;
+pub const STATUS_MAX_SIZE: usize = 48;
+
+/// Sequence number of an unconfirmed image that has been booted already
+#[ram(rtc_fast, persistent)]
+static mut BOOTED_UNCONFIRMED: u32 = 0;
+
+static PENDING_VERIFY: AtomicBool = AtomicBool::new(false);
+
+/// Reason for rejecting an OTA write, sent as application ATT error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OtaError {
+    InvalidCommand = 0x81,
+    NotStarted = 0x82,
+    TooLarge = 0x83,
+    /// Chunks have to be written in order, see [`OtaStatus::offset`]
+    UnexpectedOffset = 0x84,
+    Unaligned = 0x85,
+    Incomplete = 0x86,
+    HashMismatch = 0x87,
+    InvalidSignature = 0x88,
+    Flash = 0x89,
+    /// Image is built for the other device
+    WrongTarget = 0x8a,
+    /// Version is below the anti-rollback counter
+    Downgrade = 0x8b,
+}
+
+/// What to do after a successful control write
+#[derive(Debug, PartialEq, Eq)]
+pub enum Next {
+    Continue,
+    /// The new image is activated and booted by a reset
+    Reboot,
+}
+
+/// Returned by the status characteristic, postcard encoded
+#[derive(Debug, Serialize)]
+pub struct OtaStatus {
+    /// App slot the running image was booted from
+    pub running_slot: u8,
+    /// Start of the SHA-256 of the running image, zero if it was flashed over
+    /// USB
+    pub running_hash: [u8; 20],
+    pub confirmed: bool,
+    /// Size of the image being uploaded, zero if there is none
+    pub size: u32,
+    /// Bytes of the image written so far
+    pub offset: u32,
+}
+
+/// `esp_ota_select_entry_t`
+///
+/// The bootloader boots the slot of the valid entry with the highest sequence
+/// number.
+#[derive(Debug, Clone)]
+struct SelectEntry {
+    seq: u32,
+    /// Not used by the bootloader, holds the start of the image hash
+    label: [u8; 20],
+    state: u32,
+    crc: u32,
+}
+
+impl SelectEntry {
+    const SIZE: usize = 32;
+
+    fn new(seq: u32, label: [u8; 20], state: u32) -> Self {
+        Self {
+            seq,
+            label,
+            state,
+            crc: crc32_le(u32::MAX, &seq.to_le_bytes()),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Self {
+            seq: word(0),
+            label: bytes[4..24].try_into().unwrap(),
+            state: word(24),
+            crc: word(28),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[4..24].copy_from_slice(&self.label);
+        bytes[24..28].copy_from_slice(&self.state.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn is_valid(&self) -> bool {
+        self.seq != 0
+            && self.seq != u32::MAX
+            && self.crc == crc32_le(u32::MAX, &self.seq.to_le_bytes())
+            && !matches!(self.state, STATE_INVALID | STATE_ABORTED)
+    }
+
+    fn slot(&self) -> usize {
+        ((self.seq - 1) % SLOT_OFFSETS.len() as u32) as usize
+    }
+}
+
+async fn read_entry<F: NorFlash>(flash: &mut F, sector: u32) -> Result<SelectEntry, F::Error> {
+    let mut bytes = [0u8; SelectEntry::SIZE];
+    flash
+        .read(OTADATA_OFFSET + sector * SECTOR_SIZE, &mut bytes)
+        .await?;
+    Ok(SelectEntry::from_bytes(&bytes))
+}
+
+async fn read_entries<F: NorFlash>(flash: &mut F) -> Result<[SelectEntry; 2], F::Error> {
+    Ok([read_entry(flash, 0).await?, read_entry(flash, 1).await?])
+}
+
+/// The entry the bootloader used and its sector
+fn active(entries: &[SelectEntry; 2]) -> Option<(u32, &SelectEntry)> {
+    (0..)
+        .zip(entries.iter())
+        .filter(|(_, entry)| entry.is_valid())
+        .max_by_key(|(_, entry)| entry.seq)
+}
+
+async fn write_entry<F: NorFlash>(
+    flash: &mut F,
+    sector: u32,
+    entry: &SelectEntry,
+) -> Result<(), F::Error> {
+    let offset = OTADATA_OFFSET + sector * SECTOR_SIZE;
+    flash.erase(offset, offset + SECTOR_SIZE).await?;
+    flash.write(offset, &entry.to_bytes()).await
+}
+
+fn reset() -> ! {
+    esp_hal::system::software_reset();
+    #[allow(unreachable_code)]
+    loop {}
+}
+
+async fn fetch_min_version<F: NorFlash, const PAGES: usize>(
+    store: &mut Store<F, PAGES>,
+) -> MinVersion {
+    match store.fetch().await {
+        Ok(min_version) => min_version.unwrap_or_default(),
+        Err(e) => {
+            error!("Failed to load the anti-rollback counter: {e:?}");
+            MinVersion::default()
+        }
+    }
+}
+
+async fn store_min_version<F: NorFlash, const PAGES: usize>(
+    store: &mut Store<F, PAGES>,
+    min_version: &MinVersion,
+) -> Result<(), OtaError> {
+    store.store(min_version).await.map_err(|e| {
+        error!("Failed to store the anti-rollback counter: {e:?}");
+        OtaError::Flash
+    })
+}
+
+/// Forgets the version of an image that will not confirm itself anymore
+async fn discard_pending<F: NorFlash, const PAGES: usize>(store: &mut Store<F, PAGES>) {
+    let mut min_version = fetch_min_version(store).await;
+    if let Some(pending) = min_version.pending.take() {
+        warn!("Image version {pending} was not confirmed");
+        let _ = store_min_version(store, &min_version).await;
+    }
+}
+
+/// Rolls back an image that was reset before it confirmed itself, must be
+/// called on boot
+pub async fn check_boot<F: NorFlash, const PAGES: usize>(store: &mut Store<F, PAGES>) {
+    let entries = match read_entries(store.flash()).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to read otadata: {e:?}");
+            return;
+        }
+    };
+    let Some((sector, entry)) = active(&entries) else {
+        info!("No otadata, running slot 0");
+        discard_pending(store).await;
+        return;
+    };
+    let mut entry = entry.clone();
+    info!(
+        "Running slot {} (seq {}, state {})",
+        entry.slot(),
+        entry.seq,
+        entry.state
+    );
+    if !matches!(entry.state, STATE_NEW | STATE_PENDING_VERIFY) {
+        // e.g. the bootloader fell back to this image
+        discard_pending(store).await;
+        return;
+    }
+    if unsafe { BOOTED_UNCONFIRMED } == entry.seq {
+        error!(
+            "Image in slot {} did not confirm itself, rolling back",
+            entry.slot()
+        );
+        discard_pending(store).await;
+        entry.state = STATE_INVALID;
+        if let Err(e) = write_entry(store.flash(), sector, &entry).await {
+            error!("Failed to invalidate image: {e:?}");
+        }
+        reset();
+    }
+    unsafe { BOOTED_UNCONFIRMED = entry.seq };
+    if entry.state == STATE_NEW {
+        entry.state = STATE_PENDING_VERIFY;
+        if let Err(e) = write_entry(store.flash(), sector, &entry).await {
+            error!("Failed to mark image as pending: {e:?}");
+        }
+    }
+    warn!("Image is not confirmed yet");
+    PENDING_VERIFY.store(true, Ordering::Relaxed);
+}
+
+/// Resets an image that did not confirm itself in time, which rolls it back
+pub async fn confirm_timeout() -> Infallible {
+    Timer::after_secs(CONFIRM_TIMEOUT_SECS).await;
+    if PENDING_VERIFY.load(Ordering::Relaxed) {
+        error!("Image was not confirmed in time, resetting");
+        reset();
+    }
+    core::future::pending().await
+}
+
+struct Session {
+    slot: usize,
+    header: Header,
+    /// Bytes written
+    offset: u32,
+    /// End of the erased part of the slot
+    erased: u32,
+}
+
+/// State of the upload, kept across connections so an upload can be resumed
+pub struct Ota {
+    target: Target,
+    /// Raw Ed25519 public key the images have to be signed with
+    public_key: &'static [u8; 32],
+    /// Anti-rollback counter, the lowest version that is accepted
+    min_version: u32,
+    running_slot: usize,
+    running_hash: [u8; 20],
+    session: Option<Session>,
+}
+
+impl Ota {
+    pub async fn new<F: NorFlash, const PAGES: usize>(
+        store: &mut Store<F, PAGES>,
+        target: Target,
+        public_key: &'static [u8; 32],
+    ) -> Self {
+        let min_version = fetch_min_version(store).await.version;
+        let entries = read_entries(store.flash()).await;
+        let running = entries
+            .as_ref()
+            .ok()
+            .and_then(active)
+            .map(|(_, entry)| (entry.slot(), entry.label));
+        let (running_slot, running_hash) = running.unwrap_or((0, [0; 20]));
+        Self {
+            target,
+            public_key,
+            min_version,
+            running_slot,
+            running_hash,
+            session: None,
+        }
+    }
+
+    /// Marks the running image as good and raises the anti-rollback counter
+    /// to its version, does nothing if it already is
+    pub async fn confirm<F: NorFlash, const PAGES: usize>(&mut self, store: &mut Store<F, PAGES>) {
+        if !PENDING_VERIFY.load(Ordering::Relaxed) {
+            return;
+        }
+        let entries = match read_entries(store.flash()).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read otadata: {e:?}");
+                return;
+            }
+        };
+        let Some((sector, entry)) = active(&entries) else {
+            return;
+        };
+        let mut entry = entry.clone();
+        entry.state = STATE_VALID;
+        if let Err(e) = write_entry(store.flash(), sector, &entry).await {
+            error!("Failed to confirm image: {e:?}");
+            return;
+        }
+        info!("Confirmed image in slot {}", entry.slot());
+        PENDING_VERIFY.store(false, Ordering::Relaxed);
+        unsafe { BOOTED_UNCONFIRMED = 0 };
+
+        let mut min_version = fetch_min_version(store).await;
+        if let Some(pending) = min_version.pending.take() {
+            min_version.version = min_version.version.max(pending);
+            info!(
+                "Raising the anti-rollback counter to {}",
+                min_version.version
+            );
+            if store_min_version(store, &min_version).await.is_ok() {
+                self.min_version = min_version.version;
+            }
+        }
+    }
+
+    pub fn status(&self) -> heapless::Vec<u8, STATUS_MAX_SIZE> {
+        let (size, offset) = self
+            .session
+            .as_ref()
+            .map(|s| (s.header.size, s.offset))
+            .unwrap_or_default();
+        let status = OtaStatus {
+            running_slot: self.running_slot as u8,
+            running_hash: self.running_hash,
+            confirmed: !PENDING_VERIFY.load(Ordering::Relaxed),
+            size,
+            offset,
+        };
+        let mut buffer = [0u8; STATUS_MAX_SIZE];
+        match to_slice(&status, &mut buffer) {
+            Ok(encoded) => heapless::Vec::from_slice(encoded).unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to encode OTA status: {e:?}");
+                heapless::Vec::new()
+            }
+        }
+    }
+
+    pub async fn control<F: NorFlash, const PAGES: usize>(
+        &mut self,
+        store: &mut Store<F, PAGES>,
+        data: &[u8],
+    ) -> Result<Next, OtaError> {
+        match data.first() {
+            Some(&BEGIN) => self.begin(&data[1..]).map(|_| Next::Continue),
+            Some(&FINISH) => self.finish(store).await.map(|_| Next::Reboot),
+            Some(&ABORT) => {
+                info!("OTA aborted");
+                self.session = None;
+                Ok(Next::Continue)
+            }
+            _ => Err(OtaError::InvalidCommand),
+        }
+    }
+
+    fn begin(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        if data.len() != HEADER_LEN {
+            return Err(OtaError::InvalidCommand);
+        }
+        let header = Header::parse(data).map_err(|e| {
+            warn!("Invalid OTA header: {e:?}");
+            OtaError::InvalidCommand
+        })?;
+        self.verify(&header)?;
+        if header.target != self.target {
+            warn!("Rejected OTA image for {:?}", header.target);
+            return Err(OtaError::WrongTarget);
+        }
+        if header.version < self.min_version {
+            warn!(
+                "Rejected OTA image version {} below {}",
+                header.version, self.min_version
+            );
+            return Err(OtaError::Downgrade);
+        }
+        let size = header.size;
+        if size == 0 || size > SLOT_SIZE {
+            return Err(OtaError::TooLarge);
+        }
+        if let Some(session) = self.session.as_ref().filter(|s| s.header == header) {
+            info!("Resuming OTA at {}/{size}", session.offset);
+            return Ok(());
+        }
+        let slot = (self.running_slot + 1) % SLOT_OFFSETS.len();
+        info!(
+            "Starting OTA of version {} with {size} bytes into slot {slot}",
+            header.version
+        );
+        self.session = Some(Session {
+            slot,
+            header,
+            offset: 0,
+            erased: 0,
+        });
+        Ok(())
+    }
+
+    pub async fn write<F: NorFlash, const PAGES: usize>(
+        &mut self,
+        store: &mut Store<F, PAGES>,
+        data: &[u8],
+    ) -> Result<(), OtaError> {
+        let session = self.session.as_mut().ok_or(OtaError::NotStarted)?;
+        if data.len() <= 4 {
+            return Err(OtaError::InvalidCommand);
+        }
+        let offset = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let chunk = &data[4..];
+        if offset != session.offset {
+            return Err(OtaError::UnexpectedOffset);
+        }
+        let end = offset + chunk.len() as u32;
+        if end > session.header.size {
+            return Err(OtaError::TooLarge);
+        }
+        if chunk.len() % 4 != 0 && end != session.header.size {
+            return Err(OtaError::Unaligned);
+        }
+        let base = SLOT_OFFSETS[session.slot];
+        while session.erased < end {
+            store
+                .flash()
+                .erase(base + session.erased, base + session.erased + SECTOR_SIZE)
+                .await
+                .map_err(flash_error)?;
+            session.erased += SECTOR_SIZE;
+        }
+        // the flash can only be written in words, pad the last chunk
+        let mut padded = [0xff; DATA_MAX_SIZE];
+        padded[..chunk.len()].copy_from_slice(chunk);
+        let len = chunk.len().next_multiple_of(4);
+        store
+            .flash()
+            .write(base + offset, &padded[..len])
+            .await
+            .map_err(flash_error)?;
+        session.offset = end;
+        Ok(())
+    }
+
+    async fn finish<F: NorFlash, const PAGES: usize>(
+        &mut self,
+        store: &mut Store<F, PAGES>,
+    ) -> Result<(), OtaError> {
+        let session = self.session.as_ref().ok_or(OtaError::NotStarted)?;
+        let header = &session.header;
+        if session.offset != header.size {
+            return Err(OtaError::Incomplete);
+        }
+        // hash what is actually in the flash
+        let base = SLOT_OFFSETS[session.slot];
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 256];
+        let mut position = 0;
+        while position < header.size {
+            let len = (header.size - position).min(buffer.len() as u32) as usize;
+            store
+                .flash()
+                .read(base + position, &mut buffer[..len.next_multiple_of(4)])
+                .await
+                .map_err(flash_error)?;
+            hasher.update(&buffer[..len]);
+            position += len as u32;
+        }
+        let digest: [u8; 32] = hasher.finalize().into();
+        if digest != header.digest {
+            error!("OTA image hash mismatch, discarding it");
+            self.session = None;
+            return Err(OtaError::HashMismatch);
+        }
+        self.verify(header)?;
+
+        // raised once the image confirmed itself
+        let min_version = MinVersion {
+            version: self.min_version,
+            pending: Some(header.version),
+        };
+        store_min_version(store, &min_version).await?;
+
+        let entries = read_entries(store.flash()).await.map_err(flash_error)?;
+        let (sector, mut seq) = match active(&entries) {
+            Some((sector, entry)) => ((sector + 1) % 2, entry.seq + 1),
+            None => (0, 1),
+        };
+        while (seq - 1) as usize % SLOT_OFFSETS.len() != session.slot {
+            seq += 1;
+        }
+        let label = digest[..20].try_into().unwrap();
+        write_entry(
+            store.flash(),
+            sector,
+            &SelectEntry::new(seq, label, STATE_NEW),
+        )
+        .await
+        .map_err(flash_error)?;
+        info!(
+            "Activated OTA image version {} in slot {} (seq {seq})",
+            header.version, session.slot
+        );
+        self.session = None;
+        Ok(())
+    }
+
+    fn verify(&self, header: &Header) -> Result<(), OtaError> {
+        header.verify(self.public_key).map_err(|_| {
+            warn!("Rejected OTA image with invalid signature");
+            OtaError::InvalidSignature
+        })
+    }
+}
+
+fn flash_error<E: core::fmt::Debug>(e: E) -> OtaError {
+    error!("OTA flash error: {e:?}");
+    OtaError::Flash
+}
@@ -0,0 +1,113 @@
+//! Keeps the last panic across the following reset
+//!
+//! The record lives in RTC fast memory which survives a software reset but
+//! not a power loss. It is read over BLE and cleared by writing to the
+//! characteristic.
+#![no_std]
+
+use core::{fmt::Write, panic::PanicInfo};
+
+use esp_hal::ram;
+use log::error;
+
+/// Marks [`PANIC_MESSAGE`] as valid
+const PANIC_MAGIC: u32 = 0x7061_6e63;
+const MESSAGE_LEN: usize = 160;
+const BACKTRACE_LEN: usize = 8;
+/// Size of the text returned by [`report`]
+pub const REPORT_LEN: usize = 256;
+
+#[ram(rtc_fast, persistent)]
+static mut PANIC_VALID: u32 = 0;
+#[ram(rtc_fast, persistent)]
+static mut PANIC_MESSAGE_LEN: u32 = 0;
+#[ram(rtc_fast, persistent)]
+static mut PANIC_MESSAGE: [u8; MESSAGE_LEN] = [0; MESSAGE_LEN];
+#[ram(rtc_fast, persistent)]
+static mut PANIC_BACKTRACE: [u32; BACKTRACE_LEN] = [0; BACKTRACE_LEN];
+
+/// Writes into a fixed buffer and drops what does not fit
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let free = self.buffer.len() - self.len;
+        let n = s.len().min(free);
+        self.buffer[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn record(message: core::fmt::Arguments) {
+    let backtrace = esp_backtrace::arch::backtrace();
+    // SAFETY: only accessed from the panic handler and the BLE task, which
+    // never runs again once the panic handler started
+    unsafe {
+        let buffer = &mut *core::ptr::addr_of_mut!(PANIC_MESSAGE);
+        let mut writer = Truncating { buffer, len: 0 };
+        let _ = writer.write_fmt(message);
+        PANIC_MESSAGE_LEN = writer.len as u32;
+        let addresses = &mut *core::ptr::addr_of_mut!(PANIC_BACKTRACE);
+        for (slot, address) in addresses.iter_mut().zip(backtrace.iter()) {
+            *slot = address.unwrap_or(0) as u32;
+        }
+        PANIC_VALID = PANIC_MAGIC;
+    }
+}
+
+/// Body of the `#[panic_handler]` of a firmware, `hook` runs right before the
+/// reset
+pub fn handle(info: &PanicInfo, hook: fn()) -> ! {
+    error!("{info}");
+    record(format_args!("{info}"));
+    hook();
+    esp_hal::system::software_reset();
+    #[allow(unreachable_code)]
+    loop {}
+}
+
+/// Exceptions are handled by esp-backtrace which calls `custom_halt`
+pub fn record_exception() {
+    record(format_args!("exception"));
+}
+
+/// Text of the last panic with its backtrace, empty if there was none
+pub fn report() -> heapless::Vec<u8, REPORT_LEN> {
+    let mut report = [0u8; REPORT_LEN];
+    let mut writer = Truncating {
+        buffer: &mut report,
+        len: 0,
+    };
+    // SAFETY: the panic handler does not return, so there is no concurrent
+    // write
+    unsafe {
+        if PANIC_VALID == PANIC_MAGIC {
+            let message = &*core::ptr::addr_of!(PANIC_MESSAGE);
+            let len = (PANIC_MESSAGE_LEN as usize).min(MESSAGE_LEN);
+            let message = &message[..len];
+            // the message might be truncated within a character
+            let valid = match core::str::from_utf8(message) {
+                Ok(valid) => valid,
+                Err(e) => core::str::from_utf8(&message[..e.valid_up_to()]).unwrap_or_default(),
+            };
+            let _ = writer.write_str(valid);
+            let _ = writer.write_str("\nbacktrace:");
+            for address in (*core::ptr::addr_of!(PANIC_BACKTRACE))
+                .iter()
+                .filter(|a| **a != 0)
+            {
+                let _ = write!(writer, " {address:#x}");
+            }
+        }
+    }
+    let len = writer.len;
+    heapless::Vec::from_slice(&report[..len]).expect("report fits into the buffer")
+}
+
+pub fn clear() {
+    unsafe { PANIC_VALID = 0 };
+}
@@ -0,0 +1,84 @@
+use postcard::from_bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{Record, RecordKey};
+
+/// Keys of the bonded central
+///
+/// Centrals with a public or static address do not have to distribute an
+/// IRK, their address is the identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bond {
+    pub long_term_key: u128,
+    /// Identity address
+    pub bd_addr: [u8; 6],
+    pub identity_resolving_key: Option<u128>,
+}
+
+/// Bond in the map of older firmwares, which required an IRK
+#[derive(Deserialize)]
+struct LegacyBond {
+    long_term_key: u128,
+    bd_addr: [u8; 6],
+    identity_resolving_key: u128,
+}
+
+impl Bond {
+    /// Converts the value stored under the legacy `BOND` key
+    pub(crate) fn from_legacy(data: &[u8]) -> Option<Self> {
+        let bond: LegacyBond = from_bytes(data).ok()?;
+        Some(Self {
+            long_term_key: bond.long_term_key,
+            bd_addr: bond.bd_addr,
+            identity_resolving_key: Some(bond.identity_resolving_key),
+        })
+    }
+}
+
+impl Record for Bond {
+    const KEY: RecordKey = RecordKey::Bond;
+    const VERSION: u8 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sequential_storage::mock_flash::MockFlashBase;
+
+    use super::*;
+    use crate::Store;
+
+    type Flash = MockFlashBase<2, 4, 1024>;
+
+    fn open(flash: Flash) -> Store<Flash, 2> {
+        block_on(Store::open(flash, Flash::FULL_FLASH_RANGE))
+    }
+
+    /// Stores `bond` and reads it back after a restart
+    fn restart_with(bond: &Bond) -> Option<Bond> {
+        let mut store = open(Flash::default());
+        block_on(store.store(bond)).unwrap();
+        let mut store = open(store.flash().clone());
+        block_on(store.fetch()).unwrap()
+    }
+
+    #[test]
+    fn bond_with_irk() {
+        let bond = Bond {
+            long_term_key: 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+            bd_addr: [0xc1, 2, 3, 4, 5, 6],
+            identity_resolving_key: Some(42),
+        };
+        assert_eq!(restart_with(&bond), Some(bond));
+    }
+
+    #[test]
+    fn bond_without_irk() {
+        let bond = Bond {
+            long_term_key: 7,
+            bd_addr: [1, 2, 3, 4, 5, 6],
+            identity_resolving_key: None,
+        };
+        assert_eq!(restart_with(&bond), Some(bond));
+    }
+}
@@ -0,0 +1,487 @@
+//! Typed records persisted in a `sequential_storage` map
+//!
+//! Every record type implements [`Record`], which names its [`RecordKey`] and
+//! the version of its layout. Values are stored as the version byte followed by
+//! the postcard encoding, so a record written by an older firmware can be
+//! migrated when it is read.
+#![no_std]
+
+use core::ops::Range;
+
+use embedded_storage_async::nor_flash::NorFlash;
+use log::warn;
+use postcard::{from_bytes, to_slice};
+use sequential_storage::{
+    cache::{KeyPointerCache, NoCache},
+    erase_all,
+    map::{fetch_item, store_item, Key, SerializationError},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use bond::Bond;
+pub use ota::MinVersion;
+pub use sequential_storage::Error;
+
+mod bond;
+mod ota;
+pub mod settings;
+
+/// Largest encoded record including its key and version
+pub const MAX_RECORD_SIZE: usize = 128;
+/// Number of [`RecordKey`] variants
+const KEYS: usize = 5;
+
+/// Name of a record
+///
+/// The map deserializes every key while searching for an item, so all records
+/// share this key type. Variants must never be renumbered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKey {
+    Bond,
+    Diagnostics,
+    /// Anti-rollback counter of the OTA images
+    OtaVersion,
+    Settings,
+    /// Last accepted counter of the authenticated engine commands
+    CommandCounter,
+    /// Written by a newer firmware, e.g. before an OTA rollback, and skipped
+    Unknown(u8),
+}
+
+impl From<u8> for RecordKey {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Bond,
+            2 => Self::Diagnostics,
+            3 => Self::OtaVersion,
+            4 => Self::Settings,
+            5 => Self::CommandCounter,
+            unknown => Self::Unknown(unknown),
+        }
+    }
+}
+
+impl From<RecordKey> for u8 {
+    fn from(key: RecordKey) -> Self {
+        match key {
+            RecordKey::Bond => 1,
+            RecordKey::Diagnostics => 2,
+            RecordKey::OtaVersion => 3,
+            RecordKey::Settings => 4,
+            RecordKey::CommandCounter => 5,
+            RecordKey::Unknown(unknown) => unknown,
+        }
+    }
+}
+
+impl Key for RecordKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        *buffer
+            .first_mut()
+            .ok_or(SerializationError::BufferTooSmall)? = (*self).into();
+        Ok(1)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        let key = *buffer.first().ok_or(SerializationError::BufferTooSmall)?;
+        Ok((key.into(), 1))
+    }
+
+    fn get_len(_: &[u8]) -> Result<usize, SerializationError> {
+        Ok(1)
+    }
+}
+
+/// Four letter key of the map written by firmwares before [`RecordKey`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LegacyKey([u8; 4]);
+
+impl LegacyKey {
+    /// The only record older firmwares stored
+    const BOND: Self = Self(*b"BOND");
+}
+
+impl Key for LegacyKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        self.0.serialize_into(buffer)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        // items with a `RecordKey` can be shorter than a legacy key, they never
+        // match one
+        match <[u8; 4]>::deserialize_from(buffer) {
+            Ok((key, len)) => Ok((Self(key), len)),
+            Err(_) => Ok((Self([0; 4]), buffer.len())),
+        }
+    }
+
+    fn get_len(buffer: &[u8]) -> Result<usize, SerializationError> {
+        Ok(buffer.len().min(4))
+    }
+}
+
+/// A value that is persisted under its own [`RecordKey`]
+pub trait Record: Serialize + DeserializeOwned {
+    const KEY: RecordKey;
+    /// Version of the layout, has to be raised whenever the layout changes
+    const VERSION: u8;
+
+    /// Converts `data` stored with an older `version`, `None` drops the record
+    fn migrate(version: u8, data: &[u8]) -> Option<Self> {
+        let _ = (version, data);
+        None
+    }
+}
+
+/// Encodes `record` the way it is stored, the version followed by postcard
+pub fn encode<'b, R: Record>(
+    record: &R,
+    buffer: &'b mut [u8],
+) -> Result<&'b [u8], SerializationError> {
+    let (version, data) = buffer
+        .split_first_mut()
+        .ok_or(SerializationError::BufferTooSmall)?;
+    *version = R::VERSION;
+    let len = to_slice(record, data)
+        .map_err(|_| SerializationError::BufferTooSmall)?
+        .len();
+    Ok(&buffer[..1 + len])
+}
+
+/// Decodes a record created by [`encode`], migrating older versions
+pub fn decode<R: Record>(data: &[u8]) -> Result<R, SerializationError> {
+    let (&version, data) = data
+        .split_first()
+        .ok_or(SerializationError::InvalidFormat)?;
+    if version == R::VERSION {
+        return from_bytes(data).map_err(|_| SerializationError::InvalidFormat);
+    }
+    warn!("Migrating {:?} from version {version}", R::KEY);
+    R::migrate(version, data).ok_or(SerializationError::InvalidFormat)
+}
+
+/// Records in `PAGES` flash pages
+pub struct Store<F, const PAGES: usize> {
+    flash: F,
+    range: Range<u32>,
+    cache: KeyPointerCache<PAGES, RecordKey, KEYS>,
+    buffer: [u8; MAX_RECORD_SIZE],
+}
+
+impl<F: NorFlash, const PAGES: usize> Store<F, PAGES> {
+    /// Opens the map in `range`
+    ///
+    /// The bond of a map written with the string key of older firmwares is
+    /// migrated. A map that cannot be read at all is erased.
+    pub async fn open(flash: F, range: Range<u32>) -> Self {
+        assert_eq!(
+            (range.end - range.start) as usize,
+            PAGES * F::ERASE_SIZE,
+            "range has to span PAGES pages"
+        );
+        let mut store = Self {
+            flash,
+            range,
+            cache: KeyPointerCache::new(),
+            buffer: [0; MAX_RECORD_SIZE],
+        };
+        let result = match store.fetch_raw(RecordKey::Bond).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => store.migrate_legacy().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Erasing unreadable records: {e:?}");
+            if let Err(e) = store.erase().await {
+                warn!("Failed to erase records: {e:?}");
+            }
+        }
+        store
+    }
+
+    async fn fetch_legacy(&mut self, key: LegacyKey) -> Result<Option<&[u8]>, Error<F::Error>> {
+        fetch_item::<LegacyKey, &[u8], _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut NoCache::new(),
+            &mut self.buffer,
+            &key,
+        )
+        .await
+    }
+
+    /// Converts a map with the [`LegacyKey`], does nothing if there is none
+    async fn migrate_legacy(&mut self) -> Result<(), Error<F::Error>> {
+        let Some(bond) = self
+            .fetch_legacy(LegacyKey::BOND)
+            .await?
+            .map(Bond::from_legacy)
+        else {
+            return Ok(());
+        };
+        warn!("Migrating the bond of the legacy map");
+        self.erase().await?;
+        match bond {
+            Some(bond) => self.store(&bond).await?,
+            None => warn!("Dropping unreadable legacy bond"),
+        }
+        Ok(())
+    }
+
+    async fn fetch_raw(&mut self, key: RecordKey) -> Result<Option<&[u8]>, Error<F::Error>> {
+        fetch_item::<RecordKey, &[u8], _>(
+            &mut self.flash,
+            self.range.clone(),
+            &mut self.cache,
+            &mut self.buffer,
+            &key,
+        )
+        .await
+    }
+
+    pub async fn fetch<R: Record>(&mut self) -> Result<Option<R>, Error<F::Error>> {
+        match self.fetch_raw(R::KEY).await? {
+            Some(data) => decode(data).map(Some).map_err(Error::SerializationError),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn store<R: Record>(&mut self, record: &R) -> Result<(), Error<F::Error>> {
+        let mut encoded = [0u8; MAX_RECORD_SIZE];
+        let encoded = encode(record, &mut encoded).map_err(Error::SerializationError)?;
+        store_item(
+            &mut self.flash,
+            self.range.clone(),
+            &mut self.cache,
+            &mut self.buffer,
+            &R::KEY,
+            &encoded,
+        )
+        .await
+    }
+
+    /// Removes all records
+    pub async fn erase(&mut self) -> Result<(), Error<F::Error>> {
+        self.cache = KeyPointerCache::new();
+        erase_all(&mut self.flash, self.range.clone()).await
+    }
+
+    /// The whole flash, e.g. for the OTA partitions
+    ///
+    /// Writing to the range of the map invalidates the cache.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use futures::executor::block_on;
+    use sequential_storage::mock_flash::MockFlashBase;
+    use serde::Deserialize;
+
+    use super::*;
+
+    /// Two pages like the firmwares use
+    type Flash = MockFlashBase<2, 4, 1024>;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Counter(u32);
+
+    impl Record for Counter {
+        const KEY: RecordKey = RecordKey::Diagnostics;
+        const VERSION: u8 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct SettingsV1 {
+        interval: u16,
+    }
+
+    impl Record for SettingsV1 {
+        const KEY: RecordKey = RecordKey::Settings;
+        const VERSION: u8 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Settings {
+        interval: u16,
+        name: heapless::String<8>,
+    }
+
+    impl Record for Settings {
+        const KEY: RecordKey = RecordKey::Settings;
+        const VERSION: u8 = 2;
+
+        fn migrate(version: u8, data: &[u8]) -> Option<Self> {
+            match version {
+                1 => {
+                    let old: SettingsV1 = from_bytes(data).ok()?;
+                    Some(Self {
+                        interval: old.interval,
+                        name: "Car".try_into().ok()?,
+                    })
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn open(flash: Flash) -> Store<Flash, 2> {
+        block_on(Store::open(flash, Flash::FULL_FLASH_RANGE))
+    }
+
+    #[test]
+    fn missing_record() {
+        let mut store = open(Flash::default());
+        assert_eq!(block_on(store.fetch::<Counter>()).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = open(Flash::default());
+        block_on(store.store(&Counter(1))).unwrap();
+        block_on(store.store(&Counter(2))).unwrap();
+        assert_eq!(
+            block_on(store.fetch::<Counter>()).unwrap(),
+            Some(Counter(2))
+        );
+
+        let mut store = open(store.flash().clone());
+        assert_eq!(
+            block_on(store.fetch::<Counter>()).unwrap(),
+            Some(Counter(2))
+        );
+    }
+
+    #[test]
+    fn records_are_independent() {
+        let mut store = open(Flash::default());
+        let settings = SettingsV1 { interval: 10 };
+        block_on(store.store(&settings)).unwrap();
+        // fills both pages so the map has to move the settings
+        for i in 0..2000 {
+            block_on(store.store(&Counter(i))).unwrap();
+        }
+        assert_eq!(
+            block_on(store.fetch::<SettingsV1>()).unwrap(),
+            Some(settings)
+        );
+        assert_eq!(
+            block_on(store.fetch::<Counter>()).unwrap(),
+            Some(Counter(1999))
+        );
+    }
+
+    #[test]
+    fn migrates_old_version() {
+        let mut store = open(Flash::default());
+        block_on(store.store(&SettingsV1 { interval: 10 })).unwrap();
+        assert_eq!(
+            block_on(store.fetch::<Settings>()).unwrap(),
+            Some(Settings {
+                interval: 10,
+                name: "Car".try_into().unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_version() {
+        let mut store = open(Flash::default());
+        block_on(store.store(&Settings {
+            interval: 10,
+            name: "Car".try_into().unwrap(),
+        }))
+        .unwrap();
+        assert!(matches!(
+            block_on(store.fetch::<SettingsV1>()),
+            Err(Error::SerializationError(_))
+        ));
+    }
+
+    #[test]
+    fn record_too_big() {
+        #[derive(Serialize, Deserialize)]
+        struct Big(heapless::Vec<u8, 256>);
+
+        impl Record for Big {
+            const KEY: RecordKey = RecordKey::Bond;
+            const VERSION: u8 = 1;
+        }
+
+        let mut store = open(Flash::default());
+        let big = Big(heapless::Vec::from_slice(&[0; 200]).unwrap());
+        assert!(matches!(
+            block_on(store.store(&big)),
+            Err(Error::SerializationError(_))
+        ));
+    }
+
+    #[test]
+    fn skips_unknown_keys() {
+        #[derive(Serialize, Deserialize)]
+        struct Newer(u32);
+
+        impl Record for Newer {
+            const KEY: RecordKey = RecordKey::Unknown(200);
+            const VERSION: u8 = 1;
+        }
+
+        let mut store = open(Flash::default());
+        block_on(store.store(&Counter(1))).unwrap();
+        block_on(store.store(&Newer(5))).unwrap();
+
+        let mut store = open(store.flash().clone());
+        assert_eq!(
+            block_on(store.fetch::<Counter>()).unwrap(),
+            Some(Counter(1))
+        );
+    }
+
+    #[test]
+    fn migrates_legacy_map() {
+        #[derive(Serialize)]
+        struct LegacyBond {
+            long_term_key: u128,
+            bd_addr: [u8; 6],
+            identity_resolving_key: u128,
+        }
+
+        let mut flash = Flash::default();
+        let mut buffer = [0; 64];
+        let bond: &[u8] = to_slice(
+            &LegacyBond {
+                long_term_key: 7,
+                bd_addr: [1, 2, 3, 4, 5, 6],
+                identity_resolving_key: 42,
+            },
+            &mut buffer,
+        )
+        .unwrap();
+        block_on(store_item(
+            &mut flash,
+            Flash::FULL_FLASH_RANGE,
+            &mut NoCache::new(),
+            &mut [0; MAX_RECORD_SIZE],
+            &LegacyKey::BOND,
+            &bond,
+        ))
+        .unwrap();
+
+        let migrated = Some(Bond {
+            long_term_key: 7,
+            bd_addr: [1, 2, 3, 4, 5, 6],
+            identity_resolving_key: Some(42),
+        });
+        let mut store = open(flash);
+        assert_eq!(block_on(store.fetch::<Bond>()).unwrap(), migrated);
+
+        // the legacy item is gone
+        let mut store = open(store.flash().clone());
+        assert_eq!(block_on(store.fetch_legacy(LegacyKey::BOND)).unwrap(), None);
+        assert_eq!(block_on(store.fetch::<Bond>()).unwrap(), migrated);
+    }
+}
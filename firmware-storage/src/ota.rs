@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Record, RecordKey};
+
+/// Anti-rollback counter, OTA images with a lower version are rejected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinVersion {
+    pub version: u32,
+    /// Version of an installed image that did not confirm itself yet, it only
+    /// raises the counter once it did
+    pub pending: Option<u32>,
+}
+
+impl Record for MinVersion {
+    const KEY: RecordKey = RecordKey::OtaVersion;
+    const VERSION: u8 = 1;
+}
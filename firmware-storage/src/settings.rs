@@ -0,0 +1,239 @@
+//! Tunables that can be changed without flashing a new image
+//!
+//! The settings are read and written encoded like the stored record, so
+//! clients can also write an older layout.
+
+use core::{cell::RefCell, fmt::Debug};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embedded_storage_async::nor_flash::NorFlash;
+use log::{error, info, warn};
+
+use crate::{decode, encode, Record, Store};
+
+/// Settings record of a firmware
+///
+/// When a field is added, the current layout is kept as `SettingsV<n>` and
+/// [`Record::migrate`] fills in the new field.
+pub trait Settings: Record + Clone + Default + Debug {
+    type Invalid: Debug;
+
+    fn validate(&self) -> Result<(), Self::Invalid>;
+}
+
+#[derive(Debug)]
+pub enum UpdateError<E> {
+    Encoding,
+    Invalid(E),
+}
+
+/// The settings in use, the defaults until [`Current::init`]
+pub struct Current<S> {
+    settings: Mutex<CriticalSectionRawMutex, RefCell<Option<S>>>,
+}
+
+impl<S> Current<S> {
+    pub const fn new() -> Self {
+        Self {
+            settings: Mutex::new(RefCell::new(None)),
+        }
+    }
+}
+
+impl<S> Default for Current<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Settings> Current<S> {
+    pub fn get(&self) -> S {
+        self.settings
+            .lock(|settings| settings.borrow().clone().unwrap_or_default())
+    }
+
+    fn set(&self, settings: S) {
+        self.settings
+            .lock(|current| current.replace(Some(settings)));
+    }
+
+    /// Loads the settings from flash, older layouts are migrated on the way
+    pub async fn init<F: NorFlash, const PAGES: usize>(&self, store: &mut Store<F, PAGES>) {
+        let settings = match store.fetch::<S>().await {
+            Ok(Some(settings)) => match settings.validate() {
+                Ok(()) => settings,
+                Err(e) => {
+                    warn!("Stored settings are invalid, using defaults: {e:?}");
+                    S::default()
+                }
+            },
+            Ok(None) => S::default(),
+            Err(e) => {
+                error!("Failed to load settings, using defaults: {e:?}");
+                S::default()
+            }
+        };
+        info!("{settings:?}");
+        self.set(settings);
+    }
+
+    /// Encoding of the current settings
+    pub fn encode<const N: usize>(&self) -> heapless::Vec<u8, N> {
+        let mut buffer = [0u8; N];
+        match encode(&self.get(), &mut buffer) {
+            Ok(encoded) => heapless::Vec::from_slice(encoded).unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to encode settings: {e:?}");
+                heapless::Vec::new()
+            }
+        }
+    }
+
+    /// Replaces the settings with the settings encoded like [`Self::encode`]
+    /// does
+    pub async fn update<F: NorFlash, const PAGES: usize>(
+        &self,
+        store: &mut Store<F, PAGES>,
+        data: &[u8],
+    ) -> Result<(), UpdateError<S::Invalid>> {
+        let settings: S = decode(data).map_err(|_| UpdateError::Encoding)?;
+        settings.validate().map_err(UpdateError::Invalid)?;
+        info!("Updating {settings:?}");
+        if let Err(e) = store.store(&settings).await {
+            error!("Failed to store settings: {e:?}");
+        }
+        self.set(settings);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use postcard::from_bytes;
+    use sequential_storage::mock_flash::MockFlashBase;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::RecordKey;
+
+    type Flash = MockFlashBase<2, 4, 1024>;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct SettingsV1 {
+        interval_ms: u16,
+    }
+
+    impl Record for SettingsV1 {
+        const KEY: RecordKey = RecordKey::Settings;
+        const VERSION: u8 = 1;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct TestSettings {
+        interval_ms: u16,
+        retries: u8,
+    }
+
+    impl Default for TestSettings {
+        fn default() -> Self {
+            Self {
+                interval_ms: 10,
+                retries: 3,
+            }
+        }
+    }
+
+    impl Record for TestSettings {
+        const KEY: RecordKey = RecordKey::Settings;
+        const VERSION: u8 = 2;
+
+        fn migrate(version: u8, data: &[u8]) -> Option<Self> {
+            match version {
+                1 => {
+                    let old: SettingsV1 = from_bytes(data).ok()?;
+                    Some(Self {
+                        interval_ms: old.interval_ms,
+                        ..Self::default()
+                    })
+                }
+                _ => None,
+            }
+        }
+    }
+
+    impl Settings for TestSettings {
+        type Invalid = ();
+
+        fn validate(&self) -> Result<(), ()> {
+            if self.interval_ms == 0 {
+                return Err(());
+            }
+            Ok(())
+        }
+    }
+
+    fn open(flash: Flash) -> Store<Flash, 2> {
+        block_on(Store::open(flash, Flash::FULL_FLASH_RANGE))
+    }
+
+    #[test]
+    fn defaults_without_record() {
+        let current = Current::<TestSettings>::new();
+        block_on(current.init(&mut open(Flash::default())));
+        assert_eq!(current.get(), TestSettings::default());
+    }
+
+    #[test]
+    fn migrates_stored_settings() {
+        let mut store = open(Flash::default());
+        block_on(store.store(&SettingsV1 { interval_ms: 50 })).unwrap();
+
+        let current = Current::<TestSettings>::new();
+        block_on(current.init(&mut open(store.flash().clone())));
+        assert_eq!(
+            current.get(),
+            TestSettings {
+                interval_ms: 50,
+                retries: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn update_with_old_layout() {
+        let mut store = open(Flash::default());
+        let current = Current::<TestSettings>::new();
+        let mut buffer = [0u8; 16];
+        let old = encode(&SettingsV1 { interval_ms: 20 }, &mut buffer).unwrap();
+        block_on(current.update(&mut store, old)).unwrap();
+        assert_eq!(current.get().interval_ms, 20);
+
+        // the migrated settings are stored in the current layout
+        let reloaded = Current::<TestSettings>::new();
+        block_on(reloaded.init(&mut open(store.flash().clone())));
+        assert_eq!(reloaded.get(), current.get());
+        assert_eq!(current.encode::<16>()[0], TestSettings::VERSION);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let mut store = open(Flash::default());
+        let current = Current::<TestSettings>::new();
+        let mut buffer = [0u8; 16];
+        let invalid = TestSettings {
+            interval_ms: 0,
+            retries: 1,
+        };
+        let encoded = encode(&invalid, &mut buffer).unwrap();
+        assert!(matches!(
+            block_on(current.update(&mut store, encoded)),
+            Err(UpdateError::Invalid(()))
+        ));
+        assert!(matches!(
+            block_on(current.update(&mut store, &[TestSettings::VERSION])),
+            Err(UpdateError::Encoding)
+        ));
+        assert_eq!(current.get(), TestSettings::default());
+    }
+}
@@ -2,29 +2,35 @@ use core::{
     convert::Infallible,
     fmt::{Debug, Display},
     future::Future,
+    sync::atomic::Ordering,
 };
 
-use alloc::borrow::ToOwned;
-use embassy_embedded_hal::adapter::BlockingAsync;
-use embassy_futures::{
-    join::join,
-    select::{select, Either},
-};
-use embedded_io::Write;
+use bt_hci::{cmd::status::ReadRssi, controller::ControllerCmdSync};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_time::{with_timeout, Timer};
 use esp_hal::rng::Trng;
-use esp_storage::FlashStorage;
+use firmware_auth::{self as auth, Challenge, CHALLENGE_LEN};
+use firmware_image::Target;
+use firmware_ota::{self as ota, Next, Ota};
+use firmware_panic as panic;
+use firmware_storage::Bond;
 use log::{debug, error, info, warn};
-use postcard::{from_bytes, to_slice};
-use sequential_storage::{
-    cache::NoCache,
-    map::{fetch_item, store_item, Key, SerializationError, Value},
+use trouble_host::{
+    att::{Att, AttRsp, AttServer},
+    prelude::*,
+    BondInformation, IdentityResolvingKey, LongTermKey,
 };
-use trouble_host::{prelude::*, BondInformation, IdentityResolvingKey, LongTermKey};
 
 use crate::{
-    relay::{SIGNAL_BLE_STATE_CHANGE, SIGNAL_ENGINE_STATE},
-    schema::EngineState,
-    MAP_FLASH_RANGE,
+    command, diagnostics,
+    relay::{
+        KEY_IN_USE, SIGNAL_ARBITRATION, SIGNAL_BLE_STATE_CHANGE, SIGNAL_ENGINE_STATE,
+        SIGNAL_KEY_POSITION,
+    },
+    schema::{Arbitration, EngineState, KeyPosition},
+    settings,
+    storage::{self, Flash},
+    watchdog::{self, Task},
 };
 
 /// Max number of connections
@@ -35,30 +41,71 @@ const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 
 //const MAX_ATTRIBUTES: usize = 10;
 
+/// 32 random bytes shared with the hub, see [`auth`]
+const PAIRING_KEY: &[u8; 32] = include_bytes!(env!("PAIRING_KEY_PATH"));
+
+/// Raw Ed25519 public key the OTA images have to be signed with
+const OTA_PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("OTA_PUBLIC_KEY_PATH"));
+
 pub const ADDRESS: [u8; 6] = [0x94, 0xf1, 0xa0, 0x77, 0x4b, 0x6e];
 
 pub const ENGINE_SERVICE_UUID: [u8; 16] = [
     0x0e, 0x35, 0x35, 0x31, 0x51, 0x59, 0x42, 0xa0, 0x92, 0xff, 0x38, 0xe9, 0xe4, 0x9a, 0xb7, 0xd1,
 ];
 
-// FIXME: for some reason, if the name is longer, the advertisements fails, e.g. `CarStarter` wont work
-pub const BLE_NAME: &str = "Car";
+/// Application ATT error returned for engine states written while the physical key is in use
+pub const KEY_IN_USE_ERROR: u8 = 0x80;
+
+const DIAGNOSTICS_MAX_SIZE: usize = 128;
 
 #[gatt_service(uuid = "0e353531-5159-42a0-92ff-38e9e49ab7d1")]
 struct EngineService {
-    #[characteristic(uuid = "13d24b59-3d13-4ef7-98db-e174869078e0", read, notify, write)]
+    #[characteristic(uuid = "13d24b59-3d13-4ef7-98db-e174869078e0", read, notify)]
     engine_state: EngineState,
+    /// Engine state to switch to, authenticated as described in [`command`]
+    #[characteristic(uuid = "217a5b7d-c7fe-4cc0-bc8f-58be18c286e1", read, write)]
+    engine_command: heapless::Vec<u8, { command::COMMAND_LEN }>,
+    #[characteristic(uuid = "58939e89-92e2-43cb-8257-625dfb1128d3", read, notify)]
+    key_position: KeyPosition,
+    #[characteristic(uuid = "965b36e2-e22f-4685-841c-804c3acff58c", read, notify)]
+    arbitration: Arbitration,
+    /// postcard encoded [`diagnostics::Diagnostics`]
+    #[characteristic(uuid = "f44979f7-b189-4156-a282-153087f71c94", read)]
+    diagnostics: heapless::Vec<u8, DIAGNOSTICS_MAX_SIZE>,
+    /// Last panic as text, cleared by writing anything
+    #[characteristic(uuid = "8ad7bedf-141a-4d20-baed-15778e5aebc1", read, write)]
+    panic_record: heapless::Vec<u8, { panic::REPORT_LEN }>,
+    /// Encoded like the stored [`settings::Settings`] record
+    #[characteristic(uuid = "2b5c8e07-6d1f-4a3e-9c84-0f7a1d3e5b62", read, write)]
+    settings: heapless::Vec<u8, { settings::MAX_SIZE }>,
+    /// Challenge while not bonded, empty if authorized, takes the sealed passkey, see [`auth`]
+    #[characteristic(uuid = "92f50f10-b2a8-4da1-bcb7-43493b4fa736", read, write)]
+    auth: heapless::Vec<u8, { auth::SEALED_PASSKEY_LEN }>,
+}
+
+#[gatt_service(uuid = "c6422fa8-64b3-485d-b9c3-9d86fee29183")]
+struct OtaService {
+    /// See [`ota::BEGIN`], [`ota::FINISH`] and [`ota::ABORT`]
+    #[characteristic(uuid = "e32a319f-cfa4-4838-aac3-59fde6058ee1", write)]
+    control: heapless::Vec<u8, { ota::CONTROL_MAX_SIZE }>,
+    /// Chunk of the image at an offset
+    #[characteristic(uuid = "52c97e61-f100-4892-ae26-d98e8b2bacbf", write)]
+    data: heapless::Vec<u8, { ota::DATA_MAX_SIZE }>,
+    /// postcard encoded [`ota::OtaStatus`]
+    #[characteristic(uuid = "ffb703e1-455c-486b-a902-d4a15bbad0fb", read)]
+    status: heapless::Vec<u8, { ota::STATUS_MAX_SIZE }>,
 }
 
 #[gatt_server]
 struct Server {
     engine_service: EngineService,
+    ota_service: OtaService,
 }
 
-pub async fn run<C: Controller>(
+pub async fn run<C: Controller + ControllerCmdSync<ReadRssi>>(
     controller: C,
     mut rng: Trng<'_>,
-    mut flash: BlockingAsync<FlashStorage>,
+    flash: &Flash,
 ) -> Result<(), Error> {
     let address = Address::random(ADDRESS);
 
@@ -66,7 +113,9 @@ pub async fn run<C: Controller>(
         HostResources::new();
     let stack = trouble_host::new(controller, &mut resources)
         .set_random_address(address)
-        .set_random_generator_seed(&mut rng);
+        .set_random_generator_seed(&mut rng)
+        // the passkey shown by the phone is entered by the hub, see [`auth`]
+        .set_io_capabilities(IoCapabilities::KeyboardOnly);
 
     let Host {
         mut peripheral,
@@ -74,24 +123,31 @@ pub async fn run<C: Controller>(
         ..
     } = stack.build();
 
-    if let Some(bond_info) = load_bond_info(&mut flash).await {
+    if let Some(bond_info) = load_bond_info(flash).await {
         stack.add_bond_information(bond_info)?;
     }
 
+    let settings = settings::get();
     let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: BLE_NAME,
+        name: &settings.ble_name,
         appearance: &trouble_host::prelude::appearance::control_device::GENERIC_CONTROL_DEVICE,
     }))
     .map_err(|_| Error::Other)?;
     log::info!("Bonded devices: {:#?}", stack.get_bond_information());
-    let _ = join(log_error("ble_task", ble_task(runner)), async {
+    // kept across connections to resume interrupted uploads
+    let mut ota = Ota::new(&mut *flash.lock().await, Target::Starter, OTA_PUBLIC_KEY).await;
+    // the host stops answering once the runner returned, so nothing checks in anymore
+    let _ = select(log_error("ble_task", ble_task(runner)), async {
         loop {
             log::info!("Repeat");
-            match advertise_task(&mut peripheral, &server).await {
-                Ok(conn) => {
-                    let a = gatt_task(&server, &conn, &stack, &mut flash);
+            match advertise_task(&mut peripheral, &server, &settings.ble_name).await {
+                Ok(None) => continue,
+                Ok(Some(conn)) => {
+                    let a = gatt_task(&server, &conn, &stack, flash, &mut ota, &mut rng);
                     let b = notify_task(&server, &conn);
-                    match select(a, b).await {
+                    let result = select(a, b).await;
+                    watchdog::stop(Task::Notify);
+                    match result {
                         Either::First(f) => {
                             if let Err(e) = f {
                                 return Err(e);
@@ -133,40 +189,189 @@ async fn ble_task<C: Controller>(
 async fn gatt_task<'b>(
     server: &'b Server<'_>,
     conn: &GattConnection<'_, 'b, DefaultPacketPool>,
-    stack: &Stack<'_, impl Controller, DefaultPacketPool>,
-    flash: &mut BlockingAsync<FlashStorage>,
+    stack: &Stack<'_, impl Controller + ControllerCmdSync<ReadRssi>, DefaultPacketPool>,
+    flash: &Flash,
+    ota: &mut Ota,
+    rng: &mut Trng<'_>,
 ) -> Result<(), Error> {
     info!("gatt task running");
     let engine_state = &server.engine_service.engine_state;
+    let engine_command = &server.engine_service.engine_command;
+    let diagnostics_char = &server.engine_service.diagnostics;
+    let panic_record = &server.engine_service.panic_record;
+    let settings_char = &server.engine_service.settings;
+    let ota_control = &server.ota_service.control;
+    let ota_data = &server.ota_service.data;
+    let ota_status = &server.ota_service.status;
+    let auth_char = &server.engine_service.auth;
+    // a stored bond was paired with the passkey, the encryption proves that
+    // the peer also holds its keys
+    let peer = conn.raw().peer_address();
+    let mut stored = load_bond_info(flash).await;
+    let mut authorized = stored
+        .as_ref()
+        .is_some_and(|bond_info| bond_info.identity.match_address(&peer));
+    if stored.is_none() {
+        conn.raw().set_bondable(true)?;
+    }
+    let mut random = [0u8; CHALLENGE_LEN];
+    rng.read(&mut random);
+    let challenge = Challenge::new(random);
+    // sealed passkey the hub wrote before the stack asked for it
+    let mut passkey: Option<u32> = None;
+    let mut passkey_requested = false;
     loop {
-        match conn.next().await {
-            GattConnectionEvent::Gatt { event } => match event? {
+        let Ok(event) = with_timeout(watchdog::CHECK_IN_INTERVAL, conn.next()).await else {
+            // an idle connection proves that the host still answers with a round trip
+            match conn.raw().rssi(stack).await {
+                Ok(_) => watchdog::check_in(Task::Ble),
+                Err(e) => warn!("Reading the RSSI failed: {e:?}"),
+            }
+            continue;
+        };
+        watchdog::check_in(Task::Ble);
+        // a new image that accepts an authorized connection works well enough
+        if encrypted(conn) && authorized {
+            ota.confirm(&mut *flash.lock().await).await;
+        }
+        match event {
+            GattConnectionEvent::Gatt { event } => match event {
                 GattEvent::Read(event) => {
                     if event.handle() == engine_state.handle {
                         let value = server.get(engine_state)?;
                         log::info!("Read value {value:?}");
                     }
-                    if conn.raw().encrypted() {
+                    if event.handle() == engine_command.handle {
+                        let counter = command::counter(flash).await.to_le_bytes();
+                        let value = heapless::Vec::from_slice(&counter).expect("counter fits");
+                        server.set(engine_command, &value)?;
+                    }
+                    if event.handle() == diagnostics_char.handle {
+                        let mut buffer = [0u8; DIAGNOSTICS_MAX_SIZE];
+                        if let Some(encoded) = diagnostics::encode(&mut buffer) {
+                            let value = heapless::Vec::from_slice(encoded)
+                                .expect("buffer has the same size");
+                            server.set(diagnostics_char, &value)?;
+                        }
+                    }
+                    if event.handle() == panic_record.handle {
+                        server.set(panic_record, &panic::report())?;
+                    }
+                    if event.handle() == ota_status.handle {
+                        server.set(ota_status, &ota.status())?;
+                    }
+                    if event.handle() == settings_char.handle {
+                        server.set(settings_char, &settings::encode())?;
+                    }
+                    if event.handle() == auth_char.handle {
+                        let value = if authorized {
+                            heapless::Vec::new()
+                        } else {
+                            heapless::Vec::from_slice(challenge.bytes()).expect("challenge fits")
+                        };
+                        server.set(auth_char, &value)?;
+                    }
+                    if !encrypted(conn) && stored.is_none() && event.handle() == auth_char.handle {
+                        // the hub seals the passkey of the pairing started here
+                        // with the challenge
                         event.accept()?.send().await;
-                    } else {
+                        if let Err(e) = conn.raw().request_security() {
+                            warn!("Requesting the pairing failed: {e:?}");
+                        }
+                    } else if !encrypted(conn) {
                         info!("Read rejected due to insufficient encryption");
                         event
                             .reject(AttErrorCode::INSUFFICIENT_ENCRYPTION)?
                             .send()
                             .await;
+                    } else if !authorized && event.handle() != auth_char.handle {
+                        info!("Read rejected because the bond is not authorized");
+                        event
+                            .reject(AttErrorCode::INSUFFICIENT_AUTHORISATION)?
+                            .send()
+                            .await;
+                    } else {
+                        event.accept()?.send().await;
                     }
                 }
                 GattEvent::Write(event) => {
-                    if conn.raw().encrypted() {
-                        if event.handle() == engine_state.handle {
-                            let val = match EngineState::from_gatt(event.data()) {
+                    if stored.is_none() && event.handle() == auth_char.handle {
+                        match challenge.open_passkey(PAIRING_KEY, event.data()) {
+                            Some(key) => {
+                                info!("Got the sealed passkey");
+                                event.accept()?.send().await;
+                                if passkey_requested {
+                                    input_passkey(conn, key);
+                                } else {
+                                    passkey = Some(key);
+                                }
+                            }
+                            None => {
+                                warn!("Rejected wrong sealed passkey");
+                                event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?.send().await;
+                            }
+                        }
+                    } else if encrypted(conn) && !authorized {
+                        warn!("Write rejected because the bond is not authorized");
+                        event
+                            .reject(AttErrorCode::INSUFFICIENT_AUTHORISATION)?
+                            .send()
+                            .await;
+                    } else if encrypted(conn) {
+                        if event.handle() == ota_control.handle {
+                            let result = ota.control(&mut *flash.lock().await, event.data()).await;
+                            match result {
+                                Ok(next) => {
+                                    event.accept()?.send().await;
+                                    if next == Next::Reboot {
+                                        info!("Rebooting into the new image");
+                                        // give the response some time to be sent
+                                        Timer::after_millis(500).await;
+                                        esp_hal::system::software_reset();
+                                    }
+                                }
+                                Err(e) => {
+                                    warn!("Rejected OTA control write: {e:?}");
+                                    event.reject(att_error(e as u8))?.send().await;
+                                }
+                            }
+                        } else if event.handle() == ota_data.handle {
+                            let result = ota.write(&mut *flash.lock().await, event.data()).await;
+                            match result {
+                                Ok(()) => event.accept()?.send().await,
+                                Err(e) => {
+                                    warn!("Rejected OTA data write: {e:?}");
+                                    event.reject(att_error(e as u8))?.send().await;
+                                }
+                            }
+                        } else if event.handle() == settings_char.handle {
+                            match settings::update(flash, event.data()).await {
+                                Ok(()) => event.accept()?.send().await,
+                                Err(e) => {
+                                    warn!("Rejected settings: {e:?}");
+                                    event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?.send().await;
+                                }
+                            }
+                        } else if event.handle() == panic_record.handle {
+                            info!("Panic record read by client, clearing it");
+                            panic::clear();
+                            server.set(panic_record, &heapless::Vec::new())?;
+                            event.accept()?.send().await;
+                        } else if event.handle() == engine_command.handle {
+                            let val = match command::verify(flash, event.data()).await {
                                 Ok(val) => val,
-                                Err(_) => {
-                                    log::error!("Rejected write event: {:?}", event.data());
-                                    event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?;
+                                Err(e) => {
+                                    log::error!("Rejected engine command: {e:?}");
+                                    event.reject(AttErrorCode::VALUE_NOT_ALLOWED)?.send().await;
                                     continue;
                                 }
                             };
+                            // the relay would ignore the state anyway, tell the client why
+                            if KEY_IN_USE.load(Ordering::Relaxed) {
+                                warn!("Rejected {val:?} because the physical key is in use");
+                                event.reject(att_error(KEY_IN_USE_ERROR))?.send().await;
+                                continue;
+                            }
                             SIGNAL_BLE_STATE_CHANGE.signal(val);
                             event.accept()?.send().await;
                         }
@@ -178,32 +383,64 @@ async fn gatt_task<'b>(
                             .await;
                     }
                 }
+                GattEvent::Other(event) => event.accept()?.send().await,
             },
             GattConnectionEvent::Disconnected { reason } => {
                 log::warn!("Disconnected: {reason:?}");
                 break;
             }
-            GattConnectionEvent::Bonded { bond_info } => {
-                log::info!("Bonding with new device: {bond_info:x?}");
-                if load_bond_info(flash).await.is_none() {
-                    stack.add_bond_information(bond_info.clone())?;
-                    if bond_info.identity.irk.is_some() {
-                        store_bond_info(flash, bond_info).await;
-                    }
-                    log::info!("Stored bond");
-                } else {
+            GattConnectionEvent::PassKeyInput => match passkey.take() {
+                Some(key) => input_passkey(conn, key),
+                None => {
+                    info!("Waiting for the hub to send the passkey");
+                    passkey_requested = true;
+                }
+            },
+            GattConnectionEvent::PairingComplete {
+                security_level,
+                bond,
+            } => {
+                let identity = conn.raw().peer_identity();
+                log::info!("Paired with {identity:x?}: {security_level:?}");
+                // the stack stored the keys of every pairing, only the first
+                // one with the passkey of the hub is kept
+                if let Some(stored) = stored.take() {
                     warn!(
-                        "Ignored bond from {:x?} since already bonded",
-                        bond_info.identity.bd_addr
+                        "Ignored pairing with {:x?} since already bonded",
+                        identity.bd_addr
                     );
-                    if let Err(e) = stack.remove_bond_information(bond_info.identity) {
+                    if stored.identity.match_identity(&identity) {
+                        // the stack replaced the keys of the stored bond with
+                        // the ones of the spoofing device, removing it would
+                        // drop the bond of the hub
+                        if let Err(e) = stack.add_bond_information(stored) {
+                            error!("Failed to restore the stored bond: {e:?}");
+                        }
+                    } else if let Err(e) = stack.remove_bond_information(identity) {
                         error!("Failed to remove excessive bond: {e:?}");
                     }
                     debug!("Bonds: {:x?}", stack.get_bond_information());
                     conn.raw().disconnect();
                     break;
                 }
+                match bond {
+                    Some(bond_info) if security_level.authenticated() => {
+                        info!("Bond authorized");
+                        authorized = true;
+                        store_bond_info(flash, bond_info.clone()).await;
+                        stored = Some(bond_info);
+                    }
+                    _ => {
+                        warn!("Rejected pairing without the passkey");
+                        if let Err(e) = stack.remove_bond_information(identity) {
+                            error!("Failed to remove the unauthorized bond: {e:?}");
+                        }
+                        conn.raw().disconnect();
+                        break;
+                    }
+                }
             }
+            GattConnectionEvent::PairingFailed(e) => warn!("Pairing failed: {e:?}"),
             _ => log::warn!("unhandled connection event"),
         }
     }
@@ -211,10 +448,35 @@ async fn gatt_task<'b>(
     Ok(())
 }
 
+fn encrypted(conn: &GattConnection<'_, '_, DefaultPacketPool>) -> bool {
+    conn.raw()
+        .security_level()
+        .is_ok_and(|level| level.encrypted())
+}
+
+/// ATT error with an application `code`, `trouble-host` only has constants for
+/// the codes of the specification
+fn att_error(code: u8) -> AttErrorCode {
+    /// Opcode of an ATT error response
+    const ATT_ERROR_RSP: u8 = 0x01;
+    // decoding an error response is the only public way to construct the others
+    match Att::decode(&[ATT_ERROR_RSP, 0, 0, 0, code]) {
+        Ok(Att::Server(AttServer::Response(AttRsp::Error { code, .. }))) => code,
+        _ => AttErrorCode::UNLIKELY_ERROR,
+    }
+}
+
+fn input_passkey(conn: &GattConnection<'_, '_, DefaultPacketPool>, passkey: u32) {
+    if let Err(e) = conn.pass_key_input(passkey) {
+        warn!("Entering the passkey failed: {e:?}");
+    }
+}
+
 async fn advertise_task<'a, 'b, C: Controller>(
     peripheral: &mut Peripheral<'a, C, DefaultPacketPool>,
     server: &'b Server<'_>,
-) -> Result<GattConnection<'a, 'b, DefaultPacketPool>, BleHostError<C::Error>> {
+    name: &str,
+) -> Result<Option<GattConnection<'a, 'b, DefaultPacketPool>>, BleHostError<C::Error>> {
     info!("adv task running");
     let mut adv_data = [0u8; 31];
     let mut service_uuid: [u8; 16] = ENGINE_SERVICE_UUID;
@@ -226,7 +488,7 @@ async fn advertise_task<'a, 'b, C: Controller>(
         &[
             AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
             AdStructure::ServiceUuids128(&[service_uuid]),
-            AdStructure::CompleteLocalName(BLE_NAME.as_bytes()),
+            AdStructure::CompleteLocalName(name.as_bytes()),
         ],
         &mut adv_data[..],
     )?;
@@ -241,133 +503,82 @@ async fn advertise_task<'a, 'b, C: Controller>(
         )
         .await?;
 
+    // the host answered, advertising is restarted regularly to prove it still does
+    watchdog::check_in(Task::Ble);
     info!("Advertising...");
-    let conn = advertiser.accept().await?.with_attribute_server(server)?;
+    let Ok(conn) = with_timeout(watchdog::CHECK_IN_INTERVAL, advertiser.accept()).await else {
+        return Ok(None);
+    };
+    let conn = conn?.with_attribute_server(server)?;
     info!("Got connection from {:x?}", conn.raw().peer_address());
-    Ok(conn)
+    Ok(Some(conn))
 }
 
 async fn notify_task(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, DefaultPacketPool>,
 ) -> Result<Infallible, Error> {
-    let engine_state_attr = &server.engine_service.engine_state;
+    let service = &server.engine_service;
     loop {
-        let new_state = SIGNAL_ENGINE_STATE.wait().await;
-        // notify connected clients and update engine state in BLE value store
-        if conn.raw().encrypted() {
-            engine_state_attr.notify(conn, &new_state).await?;
-        } else {
-            warn!("Not notifying because connection is not encrypted")
+        // the previous update was notified completely
+        watchdog::check_in(Task::Notify);
+        let Ok(update) = with_timeout(
+            watchdog::CHECK_IN_INTERVAL,
+            select3(
+                SIGNAL_ENGINE_STATE.wait(),
+                SIGNAL_KEY_POSITION.wait(),
+                SIGNAL_ARBITRATION.wait(),
+            ),
+        )
+        .await
+        else {
+            continue;
+        };
+        // the value store is always updated so that reads stay current, only
+        // the notification needs an encrypted connection
+        match &update {
+            Either3::First(new_state) => server.set(&service.engine_state, new_state)?,
+            Either3::Second(key_position) => server.set(&service.key_position, key_position)?,
+            Either3::Third(arbitration) => server.set(&service.arbitration, arbitration)?,
         }
-    }
-}
-
-async fn store_bond_info(flash: &mut BlockingAsync<FlashStorage>, bond_info: BondInformation) {
-    let val = BondStoreValue(bond_info);
-    let mut data_buffer = [0u8; 64];
-    log_error(
-        "store bond info",
-        store_item(
-            flash,
-            MAP_FLASH_RANGE,
-            &mut NoCache::new(),
-            &mut data_buffer,
-            &BondStoreKey,
-            &val,
-        ),
-    )
-    .await;
-}
-
-async fn load_bond_info(flash: &mut BlockingAsync<FlashStorage>) -> Option<BondInformation> {
-    let mut data_buffer = [0u8; 64];
-    let raw: Option<BondStoreValue> = fetch_item(
-        flash,
-        MAP_FLASH_RANGE,
-        &mut NoCache::new(),
-        &mut data_buffer,
-        &BondStoreKey,
-    )
-    .await
-    .map_err(|e| {
-        error!("Failed to load bond info: {e:?}");
-    })
-    .ok()
-    .flatten();
-    raw.map(|v| v.0)
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-struct BondStoreKey;
-
-const BOND_STORE_VALUE_NAME: &str = "BOND";
-
-impl Key for BondStoreKey {
-    fn serialize_into(
-        &self,
-        mut buffer: &mut [u8],
-    ) -> Result<usize, sequential_storage::map::SerializationError> {
-        buffer
-            .write(BOND_STORE_VALUE_NAME.as_bytes())
-            .map_err(|_| SerializationError::InvalidData)
-    }
-    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
-        if buffer.starts_with(BOND_STORE_VALUE_NAME.as_bytes()) {
-            Ok((Self, BOND_STORE_VALUE_NAME.len()))
-        } else {
-            Err(SerializationError::InvalidData)
+        if !encrypted(conn) {
+            warn!("Not notifying because connection is not encrypted");
+            continue;
         }
-    }
-
-    fn get_len(_: &[u8]) -> Result<usize, SerializationError> {
-        Ok(BOND_STORE_VALUE_NAME.len())
-    }
-}
-
-#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
-struct BondInfoRaw {
-    long_term_key: u128,
-    bd_addr: [u8; 6],
-    identity_resolving_key: u128,
-}
-
-impl From<BondInformation> for BondInfoRaw {
-    fn from(value: BondInformation) -> Self {
-        Self {
-            long_term_key: value.ltk.0,
-            bd_addr: value.identity.bd_addr.into_inner(),
-            identity_resolving_key: value.identity.irk.expect("must ensure irk present").0,
+        match update {
+            Either3::First(new_state) => service.engine_state.notify(conn, &new_state).await?,
+            Either3::Second(key_position) => {
+                service.key_position.notify(conn, &key_position).await?
+            }
+            Either3::Third(arbitration) => service.arbitration.notify(conn, &arbitration).await?,
         }
     }
 }
 
-impl From<BondInfoRaw> for BondInformation {
-    fn from(value: BondInfoRaw) -> Self {
-        BondInformation {
-            ltk: LongTermKey::new(value.long_term_key),
-            identity: Identity {
-                bd_addr: BdAddr::new(value.bd_addr),
-                irk: Some(IdentityResolvingKey::new(value.identity_resolving_key)),
-            },
-        }
-    }
+async fn store_bond_info(flash: &Flash, bond_info: BondInformation) {
+    let bond = Bond {
+        long_term_key: bond_info.ltk.0,
+        bd_addr: bond_info.identity.bd_addr.into_inner(),
+        identity_resolving_key: bond_info.identity.irk.map(|irk| irk.0),
+    };
+    storage::store(flash, &bond).await;
+    info!(
+        "Stored bond, IRK present: {}",
+        bond.identity_resolving_key.is_some()
+    );
 }
 
-struct BondStoreValue(BondInformation);
-
-impl<'d> Value<'d> for BondStoreValue {
-    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
-        let raw: BondInfoRaw = self.0.to_owned().into();
-        to_slice(&raw, buffer)
-            .map_err(|_| SerializationError::InvalidFormat)
-            .map(|s| s.len())
-    }
-    fn deserialize_from(buffer: &'d [u8]) -> Result<Self, SerializationError>
-    where
-        Self: Sized,
-    {
-        let raw: BondInfoRaw = from_bytes(buffer).map_err(|_| SerializationError::InvalidFormat)?;
-        Ok(Self(raw.into()))
-    }
+async fn load_bond_info(flash: &Flash) -> Option<BondInformation> {
+    let bond: Bond = storage::fetch(flash).await?;
+    // only bonds paired with the passkey are stored, older firmwares stored
+    // them once the hub proved the pairing key
+    Some(BondInformation::new(
+        Identity {
+            bd_addr: BdAddr::new(bond.bd_addr),
+            irk: bond.identity_resolving_key.map(IdentityResolvingKey::new),
+        },
+        LongTermKey::new(bond.long_term_key),
+        SecurityLevel::EncryptedAuthenticated,
+        true,
+    ))
 }
@@ -0,0 +1,57 @@
+//! Authentication of engine commands, see [`firmware_auth::command`]
+//!
+//! The last accepted counter is persisted before the relays switch, so a
+//! recorded command cannot be replayed after a reset either. Reading returns
+//! the last accepted counter.
+
+use firmware_auth::command::{Command, Rejected};
+use firmware_storage::{Record, RecordKey};
+use log::error;
+use serde::{Deserialize, Serialize};
+use trouble_host::prelude::FromGatt;
+
+use crate::{
+    schema::EngineState,
+    storage::{self, Flash},
+};
+
+pub use firmware_auth::command::COMMAND_LEN;
+
+/// 32 random bytes shared with the hub
+const COMMAND_KEY: &[u8; 32] = include_bytes!(env!("COMMAND_KEY_PATH"));
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Counter(u32);
+
+impl Record for Counter {
+    const KEY: RecordKey = RecordKey::CommandCounter;
+    const VERSION: u8 = 1;
+}
+
+#[derive(Debug)]
+pub enum InvalidCommand {
+    Rejected(Rejected),
+    State,
+    /// The counter could not be persisted
+    Storage,
+}
+
+/// Last accepted counter, the next command has to use a higher one
+pub async fn counter(flash: &Flash) -> u32 {
+    storage::fetch::<Counter>(flash)
+        .await
+        .map_or(0, |counter| counter.0)
+}
+
+/// Checks an authenticated command and uses up its counter
+pub async fn verify(flash: &Flash, data: &[u8]) -> Result<EngineState, InvalidCommand> {
+    let command = Command::verify(COMMAND_KEY, data, counter(flash).await)
+        .map_err(InvalidCommand::Rejected)?;
+    let state = EngineState::from_gatt(&[command.state]).map_err(|_| InvalidCommand::State)?;
+    // a command that cannot be marked as used could be replayed after a reset
+    if let Err(e) = flash.lock().await.store(&Counter(command.counter)).await {
+        error!("Failed to store the command counter: {e:?}");
+        return Err(InvalidCommand::Storage);
+    }
+    Ok(state)
+}
@@ -0,0 +1,182 @@
+use core::{cell::RefCell, convert::Infallible};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Instant, Timer};
+use esp_hal::{ram, rtc_cntl::reset_reason, system::Cpu};
+use firmware_storage::{Record, RecordKey};
+use log::{info, warn};
+use postcard::to_slice;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    relay::{ENGINE_CONSUMERS_OUT_PIN, ENGINE_OUT_PIN, IGNITION_OUT_PIN, RADIO_OUT_PIN},
+    schema::KeyPosition,
+    storage::{self, Flash},
+};
+
+/// Written by `custom_halt` right before the reset
+const PANIC_MARKER: u32 = 0x5041_4e43;
+
+#[ram(rtc_fast, persistent)]
+static mut PANICKED: u32 = 0;
+
+/// Changed counters are written to flash at most this often to spare the flash
+const PERSIST_INTERVAL_SECS: u64 = 5 * 60;
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> =
+    Mutex::new(RefCell::new(State::new()));
+
+/// Counters that survive resets
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Counters {
+    pub boots: u32,
+    pub panics: u32,
+    pub radio: u32,
+    pub engine: u32,
+    pub engine_consumers: u32,
+    pub ignition: u32,
+}
+
+impl Record for Counters {
+    const KEY: RecordKey = RecordKey::Diagnostics;
+    const VERSION: u8 = 1;
+}
+
+/// Key transition that is not possible by turning the key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyAnomaly {
+    pub from: KeyPosition,
+    pub to: KeyPosition,
+    pub uptime_secs: u64,
+}
+
+struct State {
+    counters: Counters,
+    dirty: bool,
+    reset_reason: Option<u8>,
+    key_anomalies: u32,
+    last_key_anomaly: Option<KeyAnomaly>,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            counters: Counters {
+                boots: 0,
+                panics: 0,
+                radio: 0,
+                engine: 0,
+                engine_consumers: 0,
+                ignition: 0,
+            },
+            dirty: false,
+            reset_reason: None,
+            key_anomalies: 0,
+            last_key_anomaly: None,
+        }
+    }
+}
+
+/// Returned by the diagnostics characteristic, postcard encoded
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub firmware_version: &'static str,
+    pub uptime_secs: u64,
+    /// Raw `SocResetReason`
+    pub reset_reason: Option<u8>,
+    pub counters: Counters,
+    pub key_anomalies: u32,
+    pub last_key_anomaly: Option<KeyAnomaly>,
+}
+
+pub fn snapshot() -> Diagnostics {
+    STATE.lock(|state| {
+        let state = state.borrow();
+        Diagnostics {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: Instant::now().as_secs(),
+            reset_reason: state.reset_reason,
+            counters: state.counters.clone(),
+            key_anomalies: state.key_anomalies,
+            last_key_anomaly: state.last_key_anomaly.clone(),
+        }
+    })
+}
+
+/// Called by the panic handler and `custom_halt`, counted on the next boot
+pub fn mark_panic() {
+    unsafe { PANICKED = PANIC_MARKER };
+}
+
+/// Counts the relay switching from unpowered to powered
+pub fn record_actuation(pin: u8) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        let counter = match pin {
+            RADIO_OUT_PIN => &mut state.counters.radio,
+            ENGINE_OUT_PIN => &mut state.counters.engine,
+            ENGINE_CONSUMERS_OUT_PIN => &mut state.counters.engine_consumers,
+            IGNITION_OUT_PIN => &mut state.counters.ignition,
+            _ => return,
+        };
+        *counter = counter.saturating_add(1);
+        state.dirty = true;
+    })
+}
+
+pub fn record_key_anomaly(from: KeyPosition, to: KeyPosition) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.key_anomalies = state.key_anomalies.saturating_add(1);
+        state.last_key_anomaly = Some(KeyAnomaly {
+            from,
+            to,
+            uptime_secs: Instant::now().as_secs(),
+        });
+    })
+}
+
+/// Restores the counters from flash and counts this boot
+pub async fn init(flash: &Flash) {
+    let mut counters: Counters = storage::fetch(flash).await.unwrap_or_default();
+    counters.boots = counters.boots.saturating_add(1);
+    let panicked = unsafe { PANICKED } == PANIC_MARKER;
+    if panicked {
+        counters.panics = counters.panics.saturating_add(1);
+        unsafe { PANICKED = 0 };
+    }
+    let reason = reset_reason(Cpu::ProCpu);
+    info!("Reset reason {reason:?}, panicked: {panicked}, {counters:?}");
+    storage::store(flash, &counters).await;
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.reset_reason = reason.map(|reason| reason as u8);
+        state.counters = counters;
+    });
+}
+
+/// Periodically writes changed counters to flash
+pub async fn persist(flash: &Flash) -> Infallible {
+    loop {
+        Timer::after_secs(PERSIST_INTERVAL_SECS).await;
+        let counters = STATE.lock(|state| {
+            let mut state = state.borrow_mut();
+            let dirty = core::mem::replace(&mut state.dirty, false);
+            dirty.then(|| state.counters.clone())
+        });
+        if let Some(counters) = counters {
+            storage::store(flash, &counters).await;
+        }
+    }
+}
+
+/// Postcard encoding of [`snapshot`]
+pub fn encode(buffer: &mut [u8]) -> Option<&[u8]> {
+    match to_slice(&snapshot(), buffer) {
+        Ok(encoded) => Some(encoded),
+        Err(e) => {
+            warn!("Failed to encode diagnostics: {e:?}");
+            None
+        }
+    }
+}
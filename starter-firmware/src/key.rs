@@ -7,7 +7,7 @@ use esp_hal::{
 };
 use log::{debug, trace, warn};
 
-use crate::schema::KeyPosition;
+use crate::{diagnostics, schema::KeyPosition, settings};
 
 pub static SIGNAL_KEY_POSITION_CHANGE: Signal<CriticalSectionRawMutex, KeyPosition> = Signal::new();
 
@@ -90,6 +90,10 @@ impl<'d> KeyListener<'d> {
                         "unsound key position from {:?} to {:?}",
                         self.last_position, key_position
                     );
+                    diagnostics::record_key_anomaly(
+                        self.last_position.clone(),
+                        key_position.clone(),
+                    );
                     key_position
                 }
             };
@@ -116,8 +120,12 @@ impl<'a, const P: u8> From<AnyPin<'a>> for Debounced<'a, P> {
             P,
             "expected pin number and pin does not match"
         );
-        // uses some default values
-        Self::new(pin, 20, Duration::from_millis(10))
+        let settings = settings::get();
+        Self::new(
+            pin,
+            settings.debounce_threshold,
+            Duration::from_millis(settings.debounce_interval_ms.into()),
+        )
     }
 }
 
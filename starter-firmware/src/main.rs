@@ -4,8 +4,7 @@
 use core::ops::Range;
 
 use bt_hci::controller::ExternalController;
-use embassy_embedded_hal::adapter::BlockingAsync;
-use embassy_futures::join::join;
+use embassy_futures::join::join5;
 use esp_backtrace as _;
 
 use esp_hal::{
@@ -13,19 +12,24 @@ use esp_hal::{
     rng::Trng,
     timer::{systimer::SystemTimer, timg::TimerGroup},
 };
-use esp_storage::FlashStorage;
 use esp_wifi::ble::controller::BleConnector;
+use firmware_hci::Hci;
 use key::KeyListener;
 use relay::RelayHandler;
 extern crate alloc;
 
 mod ble;
+mod command;
+mod diagnostics;
 mod key;
 mod relay;
 mod schema;
+mod settings;
+mod storage;
+mod watchdog;
 
-// creds,    data, nvs,     0x110000, 0x2000,
-pub const MAP_FLASH_RANGE: Range<u32> = 0x110000..(0x110000 + 0x2000);
+// creds,    data, nvs,     0x210000, 0x2000,
+pub const MAP_FLASH_RANGE: Range<u32> = 0x210000..(0x210000 + 0x2000);
 
 #[esp_hal_embassy::main]
 async fn main(spawner: embassy_executor::Spawner) {
@@ -33,6 +37,14 @@ async fn main(spawner: embassy_executor::Spawner) {
     let peripherals = esp_hal::init(esp_hal::Config::default());
     esp_alloc::heap_allocator!(size: 72 * 1024);
     esp_println::logger::init_logger_from_env();
+    // restore the engine state as early as possible to not stall a running engine
+    let mut relay_handler = RelayHandler::new(
+        peripherals.GPIO10,
+        peripherals.GPIO21,
+        peripherals.GPIO7,
+        peripherals.GPIO20,
+    );
+    relay_handler.restore();
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let trng = Trng::new(peripherals.RNG, peripherals.ADC1);
@@ -42,19 +54,17 @@ async fn main(spawner: embassy_executor::Spawner) {
     let systimer = SystemTimer::new(peripherals.SYSTIMER);
     esp_hal_embassy::init(systimer.alarm0);
 
-    let flash = FlashStorage::new();
-    let flash = BlockingAsync::new(flash);
+    let flash = storage::open().await;
+    firmware_ota::check_boot(&mut *flash.lock().await).await;
+    diagnostics::init(&flash).await;
+    settings::init(&flash).await;
 
     let bluetooth = peripherals.BT;
     let connector = BleConnector::new(&init, bluetooth);
-    let controller: ExternalController<_, 20> = ExternalController::new(connector);
+    let controller: ExternalController<_, 20> = ExternalController::new(Hci::new(connector));
+
+    let wdt = TimerGroup::new(peripherals.TIMG1).wdt;
 
-    let mut relay_handler = RelayHandler::new(
-        peripherals.GPIO10,
-        peripherals.GPIO21,
-        peripherals.GPIO7,
-        peripherals.GPIO20,
-    );
     spawner
         .spawn(key_task(
             peripherals.GPIO0,
@@ -63,7 +73,14 @@ async fn main(spawner: embassy_executor::Spawner) {
         ))
         .unwrap();
 
-    let (res, _) = join(ble::run(controller, trng, flash), relay_handler.listen()).await;
+    let (res, _, _, _, _) = join5(
+        ble::run(controller, trng, &flash),
+        relay_handler.listen(),
+        diagnostics::persist(&flash),
+        watchdog::supervise(wdt),
+        firmware_ota::confirm_timeout(),
+    )
+    .await;
     if let Err(e) = res {
         log::error!("BLE returned with error: {e:?}");
         panic!("{e:?}")
@@ -76,8 +93,15 @@ async fn key_task(radio: GPIO0<'static>, engine: GPIO3<'static>, ignition: GPIO6
     key_listener.listen().await;
 }
 
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    firmware_panic::handle(info, diagnostics::mark_panic)
+}
+
 #[no_mangle]
 extern "Rust" fn custom_halt() {
     log::error!("Paniced, resetting...");
+    firmware_panic::record_exception();
+    diagnostics::mark_panic();
     esp_hal::system::software_reset();
 }
@@ -1,24 +1,33 @@
-use core::convert::Infallible;
+use core::{
+    convert::Infallible,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Timer;
+use embassy_time::{with_timeout, Timer};
 use esp_hal::{
     gpio::{DriveMode, Level, Output, OutputConfig, Pull},
     peripherals::{GPIO10, GPIO20, GPIO21, GPIO7},
+    ram,
+    rtc_cntl::{reset_reason, SocResetReason},
+    system::Cpu,
 };
 use log::{info, warn};
 
 use crate::{
+    diagnostics,
     key::SIGNAL_KEY_POSITION_CHANGE,
-    schema::{EngineState, KeyPosition},
+    schema::{Arbitration, EngineState, KeyPosition},
+    settings,
+    watchdog::{self, Task},
 };
 
 // GPIO pin numbers
-const RADIO_OUT_PIN: u8 = 10;
-const ENGINE_OUT_PIN: u8 = 21;
-const ENGINE_CONSUMERS_OUT_PIN: u8 = 7;
-const IGNITION_OUT_PIN: u8 = 20;
+pub const RADIO_OUT_PIN: u8 = 10;
+pub const ENGINE_OUT_PIN: u8 = 21;
+pub const ENGINE_CONSUMERS_OUT_PIN: u8 = 7;
+pub const IGNITION_OUT_PIN: u8 = 20;
 
 // FIXME: use RwLock when available in embassy-sync
 // <https://github.com/embassy-rs/embassy/issues/1394>
@@ -26,6 +35,44 @@ pub static SIGNAL_ENGINE_STATE: Signal<CriticalSectionRawMutex, EngineState> = S
 
 pub static SIGNAL_BLE_STATE_CHANGE: Signal<CriticalSectionRawMutex, EngineState> = Signal::new();
 
+/// Debounced key position for BLE clients
+pub static SIGNAL_KEY_POSITION: Signal<CriticalSectionRawMutex, KeyPosition> = Signal::new();
+
+pub static SIGNAL_ARBITRATION: Signal<CriticalSectionRawMutex, Arbitration> = Signal::new();
+
+/// Set while the physical key is not off, BLE engine states are rejected then
+pub static KEY_IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// Marks [`RESTORE_STATE`] as valid in the upper bytes
+const RESTORE_MAGIC: u32 = 0x52_53_54_00;
+
+/// Engine state set over BLE, restored after a reset so a crash does not turn
+/// off the engine while driving
+#[ram(rtc_fast, persistent)]
+static mut RESTORE_STATE: u32 = 0;
+
+fn persist_state(state: &EngineState) {
+    unsafe { RESTORE_STATE = RESTORE_MAGIC | state.clone() as u32 };
+}
+
+/// The engine state to restore, only after a reset that kept the RTC memory
+fn restore_state() -> Option<EngineState> {
+    if reset_reason(Cpu::ProCpu) == Some(SocResetReason::ChipPowerOn) {
+        return None;
+    }
+    let raw = unsafe { RESTORE_STATE };
+    if raw & !0xff != RESTORE_MAGIC {
+        return None;
+    }
+    match raw & 0xff {
+        0 => Some(EngineState::Off),
+        1 => Some(EngineState::Radio),
+        2 => Some(EngineState::Engine),
+        3 => Some(EngineState::Running),
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 enum RelayState {
     Powered,
@@ -91,7 +138,7 @@ impl<'p> RelayHandler<'p> {
         let cooldown = if skip_cooldown {
             Timer::after_secs(0)
         } else {
-            Timer::after_secs(2)
+            Timer::after_millis(settings::get().relay_cooldown_ms.into())
         };
 
         match engine_state {
@@ -122,7 +169,6 @@ impl<'p> RelayHandler<'p> {
                 self.engine_running = false;
             }
             EngineState::Running => {
-                // TODO: store running status in flash in order to recover on crash so that the engine cannot turn of while driving
                 relais.ignition.unpower();
                 relais.engine.unpower();
                 relais.engine_consumers.unpower();
@@ -141,11 +187,43 @@ impl<'p> RelayHandler<'p> {
             }
         }
         info!("sending update");
+        // states set by the key are restored by the key after a reset
+        persist_state(if self.current_state_set_by_relay {
+            &engine_state
+        } else {
+            &EngineState::Off
+        });
         self.current_state = engine_state.clone();
         SIGNAL_ENGINE_STATE.signal(engine_state);
         info!("done!");
     }
 
+    /// Restores the engine state set over BLE before a reset
+    ///
+    /// A running engine is kept running by powering the relais without the
+    /// ignition, so the starter motor is never cranked.
+    pub fn restore(&mut self) {
+        let Some(state) = restore_state() else {
+            return;
+        };
+        warn!("Restoring engine state {state:?} after reset");
+        let relais = &mut self.relais;
+        match state {
+            EngineState::Off => return,
+            EngineState::Radio => relais.radio.power(),
+            EngineState::Engine | EngineState::Running => {
+                relais.radio.power();
+                relais.engine.power();
+                relais.engine_consumers.power();
+            }
+        }
+        self.engine_running = state == EngineState::Running;
+        self.current_state_set_by_relay = true;
+        self.current_state = state.clone();
+        SIGNAL_ENGINE_STATE.signal(state);
+        SIGNAL_ARBITRATION.signal(self.arbitration());
+    }
+
     #[allow(unused)]
     pub fn state(&self) -> EngineState {
         if self.engine_running {
@@ -163,14 +241,30 @@ impl<'p> RelayHandler<'p> {
         EngineState::Off
     }
 
+    pub fn arbitration(&self) -> Arbitration {
+        match (self.current_state_set_by_relay, &self.last_key_position) {
+            (false, _) => Arbitration::Key,
+            (true, KeyPosition::Off) => Arbitration::Ble,
+            (true, _) => Arbitration::BleHeldWhileKeyRadio,
+        }
+    }
+
     pub async fn listen(&mut self) -> Infallible {
         loop {
-            match select(
-                SIGNAL_KEY_POSITION_CHANGE.wait(),
-                SIGNAL_BLE_STATE_CHANGE.wait(),
+            let change = with_timeout(
+                watchdog::CHECK_IN_INTERVAL,
+                select(
+                    SIGNAL_KEY_POSITION_CHANGE.wait(),
+                    SIGNAL_BLE_STATE_CHANGE.wait(),
+                ),
             )
-            .await
-            {
+            .await;
+            // the previous change was handled completely
+            watchdog::check_in(Task::Relay);
+            let Ok(change) = change else {
+                continue;
+            };
+            match change {
                 Either::First(key_position) => {
                     info!("relay got key position change {key_position:?}");
                     // ignition imply not running
@@ -191,7 +285,9 @@ impl<'p> RelayHandler<'p> {
                         self.current_state_set_by_relay = false;
                         self.set_state(key_position.as_engine_state(), true).await;
                     }
-                    self.last_key_position = key_position;
+                    KEY_IN_USE.store(key_position != KeyPosition::Off, Ordering::Relaxed);
+                    self.last_key_position = key_position.clone();
+                    SIGNAL_KEY_POSITION.signal(key_position);
                 }
                 Either::Second(requested_engine_state) => {
                     info!("relay got ble change {requested_engine_state:?}");
@@ -203,6 +299,7 @@ impl<'p> RelayHandler<'p> {
                     }
                 }
             }
+            SIGNAL_ARBITRATION.signal(self.arbitration());
         }
     }
 }
@@ -248,6 +345,9 @@ impl<const GPIO: u8> Relay<'_, GPIO> {
     }
 
     pub fn power(&mut self) {
+        if self.is_unpowered() {
+            diagnostics::record_actuation(GPIO);
+        }
         self.pin.set_high();
     }
 
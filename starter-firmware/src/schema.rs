@@ -9,11 +9,12 @@ use trouble_host::{
 /// Represents the position in which the key would normally be as this is what is logically simulated by the relays
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
+#[repr(u8)]
 pub enum KeyPosition {
-    Off,
-    Radio,
-    Engine,
-    Ignition,
+    Off = 0,
+    Radio = 1,
+    Engine = 2,
+    Ignition = 3,
 }
 
 impl KeyPosition {
@@ -74,3 +75,77 @@ impl FromGatt for EngineState {
         })
     }
 }
+
+impl Default for KeyPosition {
+    fn default() -> Self {
+        Self::Off
+    }
+}
+
+impl AsGatt for KeyPosition {
+    const MAX_SIZE: usize = 1;
+    const MIN_SIZE: usize = 1;
+    fn as_gatt(&self) -> &'static [u8] {
+        match self {
+            Self::Off => &[0],
+            Self::Radio => &[1],
+            Self::Engine => &[2],
+            Self::Ignition => &[3],
+        }
+    }
+}
+
+impl FromGatt for KeyPosition {
+    fn from_gatt(data: &[u8]) -> Result<Self, FromGattError> {
+        Ok(match data.first().ok_or(FromGattError::InvalidLength)? {
+            0 => Self::Off,
+            1 => Self::Radio,
+            2 => Self::Engine,
+            3 => Self::Ignition,
+            _ => return Err(FromGattError::InvalidCharacter),
+        })
+    }
+}
+
+/// Who currently controls the relays
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Arbitration {
+    /// The relays follow the physical key
+    Key = 0,
+    /// The key is off and the state was set via BLE
+    Ble = 1,
+    /// The key was turned to radio while BLE held the engine, the BLE state
+    /// is kept so the engine does not stop
+    BleHeldWhileKeyRadio = 2,
+}
+
+impl Default for Arbitration {
+    fn default() -> Self {
+        Self::Key
+    }
+}
+
+impl AsGatt for Arbitration {
+    const MAX_SIZE: usize = 1;
+    const MIN_SIZE: usize = 1;
+    fn as_gatt(&self) -> &'static [u8] {
+        match self {
+            Self::Key => &[0],
+            Self::Ble => &[1],
+            Self::BleHeldWhileKeyRadio => &[2],
+        }
+    }
+}
+
+impl FromGatt for Arbitration {
+    fn from_gatt(data: &[u8]) -> Result<Self, FromGattError> {
+        Ok(match data.first().ok_or(FromGattError::InvalidLength)? {
+            0 => Self::Key,
+            1 => Self::Ble,
+            2 => Self::BleHeldWhileKeyRadio,
+            _ => return Err(FromGattError::InvalidCharacter),
+        })
+    }
+}
@@ -0,0 +1,90 @@
+//! Tunables that can be changed over BLE without flashing a new image
+//!
+//! The settings are persisted next to the bond record, see
+//! [`firmware_storage::settings`].
+
+use firmware_storage::{
+    settings::{self as stored, Current, UpdateError},
+    Record, RecordKey,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Flash;
+
+/// Longer names do not fit into the advertisement next to the service uuid
+pub const NAME_MAX_LEN: usize = 8;
+/// Size of the encoded settings
+pub const MAX_SIZE: usize = 32;
+/// The watchdog resets the relay task if switching takes much longer
+const RELAY_COOLDOWN_MAX_MS: u16 = 10_000;
+
+static SETTINGS: Current<Settings> = Current::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    /// Equal samples until a key contact counts as stable
+    pub debounce_threshold: u8,
+    /// Time between two samples of a key contact
+    pub debounce_interval_ms: u16,
+    /// Time the relays get to switch before the new state is reported
+    pub relay_cooldown_ms: u16,
+    /// Advertised name, takes effect after a reset
+    pub ble_name: heapless::String<NAME_MAX_LEN>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            debounce_threshold: 20,
+            debounce_interval_ms: 10,
+            relay_cooldown_ms: 2000,
+            // FIXME: for some reason, if the name is longer, the advertisements fails, e.g. `CarStarter` wont work
+            ble_name: heapless::String::try_from("Car").expect("name fits"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InvalidSettings {
+    Debounce,
+    RelayCooldown,
+    Name,
+}
+
+impl stored::Settings for Settings {
+    type Invalid = InvalidSettings;
+
+    fn validate(&self) -> Result<(), InvalidSettings> {
+        if self.debounce_threshold == 0 || self.debounce_interval_ms == 0 {
+            return Err(InvalidSettings::Debounce);
+        }
+        if self.relay_cooldown_ms > RELAY_COOLDOWN_MAX_MS {
+            return Err(InvalidSettings::RelayCooldown);
+        }
+        if self.ble_name.is_empty() || !self.ble_name.is_ascii() {
+            return Err(InvalidSettings::Name);
+        }
+        Ok(())
+    }
+}
+
+impl Record for Settings {
+    const KEY: RecordKey = RecordKey::Settings;
+    const VERSION: u8 = 1;
+}
+
+pub fn get() -> Settings {
+    SETTINGS.get()
+}
+
+pub async fn init(flash: &Flash) {
+    SETTINGS.init(&mut *flash.lock().await).await;
+}
+
+pub fn encode() -> heapless::Vec<u8, MAX_SIZE> {
+    SETTINGS.encode()
+}
+
+pub async fn update(flash: &Flash, data: &[u8]) -> Result<(), UpdateError<InvalidSettings>> {
+    SETTINGS.update(&mut *flash.lock().await, data).await
+}
@@ -0,0 +1,37 @@
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use esp_storage::FlashStorage;
+use firmware_storage::{Record, Store};
+use log::error;
+
+use crate::MAP_FLASH_RANGE;
+
+/// Pages of [`MAP_FLASH_RANGE`]
+const PAGES: usize = 2;
+
+/// Flash shared by the tasks, the records are kept in [`MAP_FLASH_RANGE`]
+pub type Flash = Mutex<CriticalSectionRawMutex, Store<BlockingAsync<FlashStorage>, PAGES>>;
+
+pub async fn open() -> Flash {
+    let flash = BlockingAsync::new(FlashStorage::new());
+    Mutex::new(Store::open(flash, MAP_FLASH_RANGE).await)
+}
+
+pub async fn store<R: Record>(flash: &Flash, record: &R) {
+    if let Err(e) = flash.lock().await.store(record).await {
+        error!("Failed to store {:?}: {e:?}", R::KEY);
+    }
+}
+
+pub async fn fetch<R: Record>(flash: &Flash) -> Option<R> {
+    flash
+        .lock()
+        .await
+        .fetch()
+        .await
+        .map_err(|e| {
+            error!("Failed to load {:?}: {e:?}", R::KEY);
+        })
+        .ok()
+        .flatten()
+}
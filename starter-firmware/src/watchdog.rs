@@ -0,0 +1,89 @@
+//! Software watchdog for the long running tasks
+//!
+//! Every supervised task checks in from its loop body after an await
+//! completed. A task that waits for something that may legitimately take
+//! forever waits at most [`CHECK_IN_INTERVAL`] and checks in when the wait
+//! timed out, but only after work that proves it is alive (e.g. a round trip
+//! to the BLE host). The supervisor feeds the hardware watchdog only while all
+//! running tasks checked in recently. If a task hangs, or the executor itself
+//! is blocked, the hardware watchdog resets the chip.
+
+use core::{
+    convert::Infallible,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_time::{Instant, Timer};
+use esp_hal::{
+    peripherals::TIMG1,
+    time::Duration,
+    timer::timg::{MwdtStage, Wdt},
+};
+use log::{error, info};
+
+/// How long a task waits for an outside event before it checks in
+pub const CHECK_IN_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_secs(5);
+/// A task that did not check in for this long is considered hung. Has to be
+/// longer than the slowest relay sequence.
+const TASK_TIMEOUT_SECS: u32 = 30;
+/// Time the hardware watchdog waits for the supervisor
+const HARDWARE_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Task {
+    Ble = 0,
+    Relay = 1,
+    /// Only runs while a client is connected
+    Notify = 2,
+}
+
+const TASKS: [Task; 3] = [Task::Ble, Task::Relay, Task::Notify];
+
+/// Check in of a task that is currently not running
+const STOPPED: u32 = u32::MAX;
+
+/// Uptime in seconds of the last check in per [`Task`]
+static CHECK_INS: [AtomicU32; TASKS.len()] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(STOPPED),
+];
+
+fn uptime_secs() -> u32 {
+    Instant::now().as_secs() as u32
+}
+
+pub fn check_in(task: Task) {
+    CHECK_INS[task as usize].store(uptime_secs(), Ordering::Relaxed);
+}
+
+/// Excludes `task` from supervision until it checks in again
+pub fn stop(task: Task) {
+    CHECK_INS[task as usize].store(STOPPED, Ordering::Relaxed);
+}
+
+/// Feeds the hardware watchdog as long as all running tasks check in
+pub async fn supervise(mut wdt: Wdt<TIMG1<'static>>) -> Infallible {
+    check_in(Task::Ble);
+    check_in(Task::Relay);
+    wdt.set_timeout(
+        MwdtStage::Stage0,
+        Duration::from_secs(HARDWARE_TIMEOUT_SECS),
+    );
+    wdt.enable();
+    info!("Watchdog enabled");
+    loop {
+        let now = uptime_secs();
+        let hung = TASKS.into_iter().find(|task| {
+            let last = CHECK_INS[*task as usize].load(Ordering::Relaxed);
+            last != STOPPED && now.saturating_sub(last) > TASK_TIMEOUT_SECS
+        });
+        match hung {
+            None => wdt.feed(),
+            Some(task) => {
+                error!("{task:?} task did not check in, waiting for the watchdog reset");
+            }
+        }
+        Timer::after_secs(1).await;
+    }
+}